@@ -0,0 +1,45 @@
+use async_session::{MemoryStore, Session, SessionStore};
+use criterion::{async_executor::AsyncStdExecutor, criterion_group, criterion_main, Criterion};
+use std::thread;
+
+/// the number of sessions that every task stores and loads again
+const SESSIONS_PER_TASK: usize = 100;
+
+async fn store_and_load(store: MemoryStore, tasks: usize) {
+    let handles: Vec<_> = (0..tasks)
+        .map(|_| {
+            let store = store.clone();
+            async_std::task::spawn(async move {
+                for _ in 0..SESSIONS_PER_TASK {
+                    let cookie_value = store.store_session(Session::new()).await.unwrap();
+                    store.load_session(cookie_value.unwrap()).await.unwrap();
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.await;
+    }
+}
+
+fn concurrent_access(c: &mut Criterion) {
+    let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+    let tasks = cores * 4;
+    let mut group = c.benchmark_group("concurrent store and load");
+
+    for (name, store) in [
+        ("single lock", MemoryStore::new()),
+        ("sharded", MemoryStore::with_shards(cores * 4)),
+    ] {
+        group.bench_function(name, |b| {
+            b.to_async(AsyncStdExecutor)
+                .iter(|| store_and_load(store.clone(), tasks))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_access);
+criterion_main!(benches);
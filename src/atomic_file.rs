@@ -0,0 +1,64 @@
+use rand::RngCore;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// the extension of the temporary files that [`write()`] creates
+pub(crate) const TEMP_EXTENSION: &str = "tmp";
+
+/// writes a file by writing a temporary file next to it and renaming
+/// that over the destination, so that the destination always holds
+/// either the previous or the new contents. on unix, the file can
+/// only be read and written by its owner, and the directory is synced
+/// after the rename so that the new contents survive a crash
+pub(crate) fn write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(
+        ".{:016x}.{}",
+        rand::thread_rng().next_u64(),
+        TEMP_EXTENSION
+    ));
+    let temp_path = PathBuf::from(temp_path);
+
+    let written = create(&temp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    match written.and_then(|_| fs::rename(&temp_path, path)) {
+        Ok(()) => sync_dir(path),
+        Err(error) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(error)
+        }
+    }
+}
+
+/// creates a new file that only its owner can access
+fn create(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// syncs the directory that contains a file, which makes a rename to
+/// that file durable
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
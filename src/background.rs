@@ -0,0 +1,89 @@
+use crate::{log, Result};
+use async_channel::Sender;
+use futures_lite::future;
+use std::{future::Future, time::Duration};
+
+/// Stops a background task when it is dropped, by closing the
+/// channel that the task waits on between runs.
+#[derive(Debug)]
+pub(crate) struct Stopper {
+    _sender: Sender<()>,
+}
+
+/// builds a task that runs `job` every `interval` until the returned
+/// [`Stopper`] is dropped. the task waits with the provided `sleep`
+/// function, so that it can be run on any executor
+pub(crate) fn task<S, SF, J, JF>(
+    interval: Duration,
+    sleep: S,
+    mut job: J,
+) -> (Stopper, impl Future<Output = ()> + Send + 'static)
+where
+    S: Fn(Duration) -> SF + Send + 'static,
+    SF: Future<Output = ()> + Send,
+    J: FnMut() -> JF + Send + 'static,
+    JF: Future<Output = Result> + Send,
+{
+    let (sender, receiver) = async_channel::bounded(1);
+    let task = async move {
+        loop {
+            let wait = sleep(interval);
+            let stopped = future::or(
+                async {
+                    // nothing is ever sent, so this only returns once
+                    // the stopper has been dropped
+                    let _ = receiver.recv().await;
+                    true
+                },
+                async {
+                    wait.await;
+                    false
+                },
+            )
+            .await;
+
+            if stopped {
+                log::trace!("stopping background task");
+                break;
+            }
+
+            if let Err(error) = job().await {
+                log::warn!("background task failed: {}", error);
+            }
+        }
+    };
+
+    (Stopper { _sender: sender }, task)
+}
+
+/// spawns a task that runs `job` every `interval` on the tokio
+/// runtime of the current thread if there is one, and on the
+/// async-std executor otherwise
+///
+/// # Panics
+///
+/// Panics if the `async-std` feature is disabled and this is not
+/// called from within a tokio runtime.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub(crate) fn spawn<J, JF>(interval: Duration, job: J) -> Stopper
+where
+    J: FnMut() -> JF + Send + 'static,
+    JF: Future<Output = Result> + Send,
+{
+    #[cfg(feature = "tokio")]
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        let (stopper, task) = task(interval, tokio::time::sleep, job);
+        handle.spawn(task);
+        return stopper;
+    }
+
+    #[cfg(feature = "async-std")]
+    {
+        let (stopper, task) = task(interval, async_std::task::sleep, job);
+        async_std::task::spawn(task);
+        stopper
+    }
+
+    #[cfg(not(feature = "async-std"))]
+    panic!("background tasks must be spawned from within a tokio runtime")
+}
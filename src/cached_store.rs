@@ -0,0 +1,881 @@
+use crate::{
+    async_trait, background, log, session_store::Lookup, Result, Session, SessionInfo, SessionPage,
+    SessionStore,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// the default of [`CachedStore::with_ttl`]
+const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+/// the number of cache timestamps below which expired timestamps are
+/// not pruned
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// # When a [`CachedStore`] writes sessions to its slow store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum WritePolicy {
+    /// every stored session is written to the slow store before
+    /// [`SessionStore::store_session`] returns. this is the default.
+    #[default]
+    WriteThrough,
+
+    /// stored sessions are only written to the fast store, and are
+    /// written to the slow store by [`CachedStore::flush`]. sessions
+    /// that have not been flushed yet are lost if the process exits.
+    /// if a session is stored several times between flushes, only
+    /// the last copy is written, along with the keys that changed in
+    /// any of them.
+    WriteBehind,
+}
+
+/// # A session store that caches another session store
+///
+/// Composes a fast store, such as a [`MemoryStore`](crate::MemoryStore),
+/// in front of a slow store, such as a store that is backed by a
+/// database. Sessions are loaded from the fast store if they were
+/// cached within the [TTL](CachedStore::with_ttl), and are otherwise
+/// loaded from the slow store and cached in the fast store. Stored
+/// sessions are written to both stores according to the
+/// [`WritePolicy`], and destroying a session or clearing the store
+/// affects both stores.
+///
+/// The fast store must store sessions by their id, like the
+/// [`MemoryStore`](crate::MemoryStore) does, so that sessions that
+/// were loaded from the slow store can be cached in it, and must
+/// replace the sessions it stores, which is the default
+/// [`UpdateMode`](crate::UpdateMode). The slow store may merge them
+/// instead, as it receives the keys that changed in every stored
+/// session. A short TTL
+/// bounds how long changes that other processes make to the slow
+/// store go unnoticed.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{CachedStore, FileStore, MemoryStore, Session, SessionStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// # let dir = tempfile::tempdir().unwrap();
+/// let store = CachedStore::new(MemoryStore::new(), FileStore::new(dir.path()));
+/// let mut session = Session::new();
+/// session.insert("user_id", 1)?;
+/// let cookie_value = store.store_session(session).await?.unwrap();
+///
+/// // this is served from the memory store
+/// let session = store.load_session(cookie_value).await?.unwrap();
+/// assert_eq!(session.get::<usize>("user_id"), Some(1));
+/// # Ok(()) }) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CachedStore<Fast, Slow> {
+    fast: Fast,
+    slow: Slow,
+    ttl: Duration,
+    write_policy: WritePolicy,
+    state: Arc<Mutex<CacheState>>,
+}
+
+/// The bookkeeping of a [`CachedStore`] that is not kept in its fast
+/// store.
+#[derive(Debug, Default)]
+struct CacheState {
+    /// when sessions were cached in the fast store, by id
+    cached_at: HashMap<String, Instant>,
+    /// the length of `cached_at` at which timestamps older than the
+    /// ttl are pruned
+    prune_at: usize,
+    /// sessions that were stored with [`WritePolicy::WriteBehind`]
+    /// and not yet written to the slow store, by id
+    pending: HashMap<String, Pending>,
+    /// counts the sessions that were stored behind and the sessions
+    /// that were destroyed, to tell which happened first
+    writes: u64,
+    /// the number of flushes that are writing sessions to the slow
+    /// store
+    flushing: usize,
+    /// the sessions that were destroyed while a flush was running,
+    /// which the flush must not write again
+    destroyed: Destroyed,
+}
+
+/// A session that was stored with [`WritePolicy::WriteBehind`] and
+/// not yet written to the slow store.
+#[derive(Debug)]
+struct Pending {
+    session: Session,
+    /// the value of [`CacheState::writes`] when it was stored
+    stored_at: u64,
+}
+
+/// The sessions that were destroyed while a flush was running, each
+/// with the value of [`CacheState::writes`] when they were destroyed.
+#[derive(Debug, Default)]
+struct Destroyed {
+    /// destroyed sessions, by id
+    ids: HashMap<String, u64>,
+    /// the prefixes of cleared ids, where clearing the whole store
+    /// clears the empty prefix
+    prefixes: Vec<(String, u64)>,
+    /// the principals whose sessions were destroyed
+    principals: HashMap<String, u64>,
+}
+
+impl Destroyed {
+    /// whether a pending session was destroyed after it was stored
+    fn contains(&self, pending: &Pending) -> bool {
+        let id = pending.session.id();
+        let after = |destroyed_at: &u64| *destroyed_at > pending.stored_at;
+        self.ids.get(id).is_some_and(after)
+            || self.prefixes.iter().any(|(prefix, destroyed_at)| {
+                id.starts_with(prefix.as_str()) && after(destroyed_at)
+            })
+            || pending
+                .session
+                .principal()
+                .and_then(|principal| self.principals.get(principal))
+                .is_some_and(after)
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> bool {
+        self.ids.is_empty() && self.prefixes.is_empty() && self.principals.is_empty()
+    }
+}
+
+impl CacheState {
+    fn is_fresh(&self, id: &str, ttl: Duration) -> bool {
+        self.pending.contains_key(id)
+            || self
+                .cached_at
+                .get(id)
+                .is_some_and(|cached_at| cached_at.elapsed() < ttl)
+    }
+
+    fn cache(&mut self, id: String, ttl: Duration) {
+        self.cached_at.insert(id, Instant::now());
+        if self.cached_at.len() >= self.prune_at {
+            self.cached_at
+                .retain(|_, cached_at| cached_at.elapsed() < ttl);
+            self.prune_at = (self.cached_at.len() * 2).max(MIN_PRUNE_LEN);
+        }
+    }
+
+    fn next_write(&mut self) -> u64 {
+        self.writes += 1;
+        self.writes
+    }
+
+    fn invalidate(&mut self, id: &str) {
+        self.cached_at.remove(id);
+        self.pending.remove(id);
+        if self.flushing > 0 {
+            let destroyed_at = self.next_write();
+            self.destroyed.ids.insert(id.to_owned(), destroyed_at);
+        }
+    }
+
+    fn invalidate_prefix(&mut self, prefix: &str) {
+        self.cached_at.retain(|id, _| !id.starts_with(prefix));
+        self.pending.retain(|id, _| !id.starts_with(prefix));
+        if self.flushing > 0 {
+            let destroyed_at = self.next_write();
+            self.destroyed
+                .prefixes
+                .push((prefix.to_owned(), destroyed_at));
+        }
+    }
+
+    /// forgets the sessions of a principal, given the ids of those
+    /// that are cached in the fast store
+    fn invalidate_principal(&mut self, principal: &str, cached: impl IntoIterator<Item = String>) {
+        for id in cached {
+            self.cached_at.remove(&id);
+        }
+        let cached_at = &mut self.cached_at;
+        self.pending.retain(|id, pending| {
+            let destroyed = pending.session.principal() == Some(principal);
+            if destroyed {
+                cached_at.remove(id);
+            }
+            !destroyed
+        });
+        if self.flushing > 0 {
+            let destroyed_at = self.next_write();
+            self.destroyed
+                .principals
+                .insert(principal.to_owned(), destroyed_at);
+        }
+    }
+}
+
+/// Counts a running flush for as long as it is alive, which includes
+/// flushes that are cancelled while writing.
+struct Flushing<'a>(&'a Mutex<CacheState>);
+
+impl<'a> Flushing<'a> {
+    fn start(state: &'a Mutex<CacheState>) -> (Self, HashMap<String, Pending>) {
+        let mut locked = lock(state);
+        locked.flushing += 1;
+        let pending = std::mem::take(&mut locked.pending);
+        (Self(state), pending)
+    }
+}
+
+impl Drop for Flushing<'_> {
+    fn drop(&mut self) {
+        let mut state = lock(self.0);
+        state.flushing -= 1;
+        if state.flushing == 0 {
+            state.destroyed = Destroyed::default();
+        }
+    }
+}
+
+fn lock(state: &Mutex<CacheState>) -> MutexGuard<'_, CacheState> {
+    // the state is consistent at every point where a panic could
+    // occur, so a poisoned lock can be used
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl<Fast, Slow> CachedStore<Fast, Slow>
+where
+    Fast: SessionStore + Send + Sync,
+    Slow: SessionStore + Send + Sync,
+{
+    /// constructs a new CachedStore that caches sessions of the slow
+    /// store in the fast store, with [`WritePolicy::WriteThrough`] and
+    /// a TTL of 30 seconds
+    pub fn new(fast: Fast, slow: Slow) -> Self {
+        Self {
+            fast,
+            slow,
+            ttl: DEFAULT_TTL,
+            write_policy: WritePolicy::default(),
+            state: Arc::default(),
+        }
+    }
+
+    /// sets how long a session that was cached in the fast store is
+    /// loaded from it, before it is loaded from the slow store again.
+    /// defaults to 30 seconds.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// sets when stored sessions are written to the slow store.
+    /// defaults to [`WritePolicy::WriteThrough`].
+    pub fn with_write_policy(mut self, write_policy: WritePolicy) -> Self {
+        self.write_policy = write_policy;
+        self
+    }
+
+    /// the fast store that sessions are cached in
+    pub fn fast(&self) -> &Fast {
+        &self.fast
+    }
+
+    /// the slow store that is cached
+    pub fn slow(&self) -> &Slow {
+        &self.slow
+    }
+
+    /// Writes all sessions that were stored with
+    /// [`WritePolicy::WriteBehind`] since the last flush to the slow
+    /// store. If writing a session fails, it is kept to be written
+    /// again by the next flush, and the error is returned once every
+    /// other session has been written. Sessions that are destroyed or
+    /// cleared while they are written are destroyed in the slow store
+    /// again.
+    pub async fn flush(&self) -> Result {
+        let (_flushing, pending) = Flushing::start(&self.state);
+        log::trace!("flushing {} sessions to the slow store", pending.len());
+
+        let mut result = Ok(());
+        for (id, pending) in pending {
+            if self.state().destroyed.contains(&pending) {
+                continue;
+            }
+
+            match self.slow.store_session(pending.session.detach()).await {
+                Ok(_) if self.state().destroyed.contains(&pending) => {
+                    log::trace!("destroying session `{}` again after flushing it", id);
+                    if let Err(error) = self.slow.destroy_session(pending.session).await {
+                        result = Err(error);
+                    }
+                }
+
+                Ok(_) => {}
+
+                Err(error) => {
+                    log::warn!("failed to flush session `{}`: {}", id, error);
+                    let mut state = self.state();
+                    if !state.destroyed.contains(&pending) {
+                        state.pending.entry(id).or_insert(pending);
+                    }
+                    result = Err(error);
+                }
+            }
+        }
+        result
+    }
+
+    /// Builds a task that calls [`CachedStore::flush`] every
+    /// `interval`, until the returned [`FlushHandle`] is dropped. Like
+    /// [`MemoryStore::cleanup_task`](crate::MemoryStore::cleanup_task),
+    /// the task waits with the provided `sleep` function and must be
+    /// spawned by the caller.
+    pub fn flush_task<S, F>(
+        &self,
+        interval: Duration,
+        sleep: S,
+    ) -> (
+        FlushHandle<Fast, Slow>,
+        impl Future<Output = ()> + Send + 'static,
+    )
+    where
+        Self: Clone + 'static,
+        S: Fn(Duration) -> F + Send + 'static,
+        F: Future<Output = ()> + Send,
+    {
+        let store = self.clone();
+        let (stopper, task) = background::task(interval, sleep, move || {
+            let store = store.clone();
+            async move { store.flush().await }
+        });
+
+        let handle = FlushHandle {
+            store: self.clone(),
+            _stopper: stopper,
+        };
+        (handle, task)
+    }
+
+    /// Spawns a background task that calls [`CachedStore::flush`]
+    /// every `interval`, until the returned [`FlushHandle`] is
+    /// dropped. The task is spawned like the one of
+    /// `MemoryStore::spawn_cleanup`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if only the `tokio` feature is enabled, and this is not
+    /// called from within a tokio runtime.
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    pub fn spawn_flush(&self, interval: Duration) -> FlushHandle<Fast, Slow>
+    where
+        Self: Clone + 'static,
+    {
+        let store = self.clone();
+        let stopper = background::spawn(interval, move || {
+            let store = store.clone();
+            async move { store.flush().await }
+        });
+
+        FlushHandle {
+            store: self.clone(),
+            _stopper: stopper,
+        }
+    }
+
+    /// loads the session with the provided id from the fast store if
+    /// it is fresh there, and from the slow store otherwise
+    async fn load(&self, id: String, lookup: Lookup<'_>) -> Result<Option<Session>> {
+        if self.state().is_fresh(&id, self.ttl) {
+            if let Some(session) = lookup.load(&self.fast).await? {
+                log::trace!("loaded session `{}` from the fast store", id);
+                return Ok(Some(session));
+            }
+        }
+
+        // the fast store may have evicted a session that was not
+        // flushed yet, in which case the slow store has an older copy
+        let pending = self
+            .state()
+            .pending
+            .get(&id)
+            .map(|pending| pending.session.snapshot());
+        if let Some(session) = pending {
+            log::trace!("loaded unflushed session `{}`", id);
+            return Ok(session.validate());
+        }
+
+        log::trace!("loading session `{}` from the slow store", id);
+        match lookup.load(&self.slow).await? {
+            Some(session) => {
+                // storing the session itself would reset its changes
+                // and reissue flag, which the caller still needs
+                self.fast.store_session(session.snapshot()).await?;
+                self.state().cache(id, self.ttl);
+                Ok(Some(session))
+            }
+
+            None => {
+                self.state().cached_at.remove(&id);
+                Ok(None)
+            }
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        lock(&self.state)
+    }
+}
+
+#[async_trait]
+impl<Fast, Slow> SessionStore for CachedStore<Fast, Slow>
+where
+    Fast: SessionStore + Send + Sync,
+    Slow: SessionStore + Send + Sync,
+{
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        self.load(id, Lookup::CookieValue(&cookie_value)).await
+    }
+
+    async fn load_session_by_id(&self, id: &str) -> Result<Option<Session>> {
+        self.load(id.to_owned(), Lookup::Id(id)).await
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        let id = session.id().to_owned();
+        match self.write_policy {
+            WritePolicy::WriteThrough => {
+                self.fast.store_session(session.detach()).await?;
+                match self.slow.store_session(session).await {
+                    Ok(cookie_value) => {
+                        self.state().cache(id, self.ttl);
+                        Ok(cookie_value)
+                    }
+
+                    Err(error) => {
+                        // the fast store must not serve a session
+                        // that the slow store does not have
+                        self.state().invalidate(&id);
+                        Err(error)
+                    }
+                }
+            }
+
+            WritePolicy::WriteBehind => {
+                let pending = session.detach();
+                let cookie_value = self.fast.store_session(session).await?;
+                let mut state = self.state();
+                if let Some(earlier) = state.pending.get(&id) {
+                    pending.include_changes(&earlier.session);
+                }
+                let pending = Pending {
+                    session: pending,
+                    stored_at: state.next_write(),
+                };
+                state.pending.insert(id.clone(), pending);
+                state.cache(id, self.ttl);
+                Ok(cookie_value)
+            }
+        }
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        self.state().invalidate(session.id());
+        self.fast.destroy_session(session.clone()).await?;
+        self.slow.destroy_session(session).await
+    }
+
+    async fn clear_store(&self) -> Result {
+        self.state().invalidate_prefix("");
+        self.fast.clear_store().await?;
+        self.slow.clear_store().await
+    }
+
+    async fn clear_prefix(&self, prefix: &str) -> Result {
+        self.state().invalidate_prefix(prefix);
+        self.fast.clear_prefix(prefix).await?;
+        self.slow.clear_prefix(prefix).await
+    }
+
+    /// Sessions are listed from the slow store, after sessions that
+    /// were stored with [`WritePolicy::WriteBehind`] are flushed to it.
+    async fn list_sessions(&self, cursor: Option<&str>, limit: usize) -> Result<SessionPage> {
+        self.flush().await?;
+        self.slow.list_sessions(cursor, limit).await
+    }
+
+    /// Sessions are listed from the slow store, after sessions that
+    /// were stored with [`WritePolicy::WriteBehind`] are flushed to it.
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<SessionPage> {
+        self.flush().await?;
+        self.slow.list_prefix(prefix, cursor, limit).await
+    }
+
+    /// Sessions are listed from the slow store, after sessions that
+    /// were stored with [`WritePolicy::WriteBehind`] are flushed to it.
+    async fn list_principal_sessions(&self, principal: &str) -> Result<Vec<SessionInfo>> {
+        self.flush().await?;
+        self.slow.list_principal_sessions(principal).await
+    }
+
+    /// The sessions of the principal are listed from the fast store
+    /// as well, to stop serving them from it.
+    async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+        self.flush().await?;
+        let cached = self.fast.list_principal_sessions(principal).await?;
+        self.state().invalidate_principal(
+            principal,
+            cached.into_iter().map(|info| info.id().to_owned()),
+        );
+        self.fast.destroy_principal_sessions(principal).await?;
+        self.slow.destroy_principal_sessions(principal).await
+    }
+}
+
+/// # A handle to a flush task of a [`CachedStore`]
+///
+/// The task is stopped when this handle is dropped. See
+/// [`CachedStore::flush_task`].
+#[derive(Debug)]
+#[must_use = "the flush task is stopped when its handle is dropped"]
+pub struct FlushHandle<Fast, Slow> {
+    store: CachedStore<Fast, Slow>,
+    _stopper: background::Stopper,
+}
+
+impl<Fast, Slow> FlushHandle<Fast, Slow>
+where
+    Fast: SessionStore + Send + Sync,
+    Slow: SessionStore + Send + Sync,
+{
+    /// Flushes immediately, without waiting for the next interval.
+    /// This can be used to flush a final time on shutdown.
+    pub async fn tick(&self) -> Result {
+        self.store.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncryptedStore, FileStore, Keyring, MemoryStore, UpdateMode};
+    use async_std::task;
+
+    fn store() -> CachedStore<MemoryStore, MemoryStore> {
+        CachedStore::new(MemoryStore::new(), MemoryStore::new())
+    }
+
+    #[async_std::test]
+    async fn reading_through_the_fast_store() -> Result {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CachedStore::new(MemoryStore::new(), FileStore::new(dir.path()));
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let cookie_value = store.slow().store_session(session).await?.unwrap();
+        assert_eq!(0, store.fast().count().await);
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        assert_eq!(1, store.fast().count().await);
+
+        // changes to the slow store are only seen after the ttl, which
+        // clearing the cache timestamps stands in for
+        let mut session = store
+            .slow()
+            .load_session(cookie_value.clone())
+            .await?
+            .unwrap();
+        session.insert("key", "changed")?;
+        store.slow().store_session(session).await?;
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        store.state().cached_at.clear();
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!("changed", session.get::<String>("key").unwrap());
+
+        let session = store
+            .slow()
+            .load_session(cookie_value.clone())
+            .await?
+            .unwrap();
+        store.slow().destroy_session(session).await?;
+        assert!(store.load_session(cookie_value.clone()).await?.is_some());
+        store.state().cached_at.clear();
+        assert!(store.load_session(cookie_value).await?.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn keeping_the_flags_of_sessions_read_through() -> Result {
+        let inner = MemoryStore::new();
+        let old = EncryptedStore::new(inner.clone(), &[1; 32]);
+        let cookie_value = old.store_session(Session::new()).await?.unwrap();
+
+        let slow = EncryptedStore::new(inner, Keyring::new(&[2; 32]).with_retired(&[1; 32]));
+        let store = CachedStore::new(MemoryStore::new(), slow);
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert!(session.should_reissue());
+        assert!(session.data_changed());
+        assert_eq!(1, store.fast().count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn writing_through() -> Result {
+        let store = store();
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        assert_eq!(1, store.fast().count().await);
+        assert!(store.slow().load_session(cookie_value).await?.is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn writing_behind() -> Result {
+        let store = store()
+            .with_ttl(Duration::ZERO)
+            .with_write_policy(WritePolicy::WriteBehind);
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        assert!(store
+            .slow()
+            .load_session(cookie_value.clone())
+            .await?
+            .is_none());
+
+        // unflushed sessions are served from the fast store
+        assert!(store.load_session(cookie_value.clone()).await?.is_some());
+
+        let (flush, _) = store.flush_task(Duration::from_secs(3600), task::sleep);
+        flush.tick().await?;
+        assert!(store.slow().load_session(cookie_value).await?.is_some());
+        assert!(store.state().pending.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn merging_into_the_slow_store() -> Result {
+        for write_policy in [WritePolicy::WriteThrough, WritePolicy::WriteBehind] {
+            let slow = MemoryStore::new().with_update_mode(UpdateMode::Merge);
+            let store = CachedStore::new(MemoryStore::new(), slow).with_write_policy(write_policy);
+            let cookie_value = store.store_session(Session::new()).await?.unwrap();
+            store.flush().await?;
+
+            // another process changes the slow store
+            let mut session = store
+                .slow()
+                .load_session(cookie_value.clone())
+                .await?
+                .unwrap();
+            session.insert("other", 1)?;
+            store.slow().store_session(session).await?;
+
+            let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+            session.insert("first", 1)?;
+            store.store_session(session).await?;
+            let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+            session.insert("second", 2)?;
+            store.store_session(session).await?;
+            store.flush().await?;
+
+            let session = store.slow().load_session(cookie_value).await?.unwrap();
+            let mut keys: Vec<_> = session.data().into_keys().collect();
+            keys.sort();
+            assert_eq!(vec!["first", "other", "second"], keys);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn loading_unflushed_sessions_evicted_from_the_fast_store() -> Result {
+        let store = CachedStore::new(MemoryStore::with_capacity(1), MemoryStore::new())
+            .with_write_policy(WritePolicy::WriteBehind);
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let cookie_value = store.store_session(session).await?.unwrap();
+        store.store_session(Session::new()).await?;
+        assert_eq!(1, store.fast().count().await);
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        store.flush().await?;
+        assert!(store.slow().load_session(cookie_value).await?.is_some());
+        Ok(())
+    }
+
+    /// A slow store whose writes wait until the test lets them
+    /// through, so that the test can act while a flush is writing.
+    #[derive(Clone)]
+    struct Gated {
+        inner: MemoryStore,
+        started: async_channel::Sender<()>,
+        release: async_channel::Receiver<()>,
+    }
+
+    /// a store that writes behind to a gated slow store, along with
+    /// a receiver of the writes that started and a sender that lets
+    /// one write through
+    fn gated() -> (
+        CachedStore<MemoryStore, Gated>,
+        async_channel::Receiver<()>,
+        async_channel::Sender<()>,
+    ) {
+        let (started, writing) = async_channel::unbounded();
+        let (releaser, release) = async_channel::unbounded();
+        let slow = Gated {
+            inner: MemoryStore::new(),
+            started,
+            release,
+        };
+        let store =
+            CachedStore::new(MemoryStore::new(), slow).with_write_policy(WritePolicy::WriteBehind);
+        (store, writing, releaser)
+    }
+
+    #[async_trait]
+    impl SessionStore for Gated {
+        async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+            self.inner.load_session(cookie_value).await
+        }
+        async fn store_session(&self, session: Session) -> Result<Option<String>> {
+            self.started.send(()).await.unwrap();
+            self.release.recv().await.unwrap();
+            self.inner.store_session(session).await
+        }
+        async fn destroy_session(&self, session: Session) -> Result {
+            self.inner.destroy_session(session).await
+        }
+        async fn clear_store(&self) -> Result {
+            self.inner.clear_store().await
+        }
+        async fn clear_prefix(&self, prefix: &str) -> Result {
+            self.inner.clear_prefix(prefix).await
+        }
+        async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+            self.inner.destroy_principal_sessions(principal).await
+        }
+    }
+
+    #[async_std::test]
+    async fn destroying_sessions_while_flushing() -> Result {
+        let (store, writing, release) = gated();
+        let session = Session::new();
+        store.store_session(session.clone()).await?;
+
+        let flush = task::spawn({
+            let store = store.clone();
+            async move { store.flush().await }
+        });
+        writing.recv().await.unwrap();
+        store.destroy_session(session).await?;
+        release.send(()).await.unwrap();
+        flush.await?;
+
+        assert_eq!(0, store.slow().inner.count().await);
+        assert!(store.state().destroyed.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn clearing_sessions_while_flushing() -> Result {
+        let (store, writing, release) = gated();
+        let mut cleared = Session::new();
+        cleared.set_id(format!("app.{}", cleared.id()));
+        store.store_session(cleared).await?;
+        let kept = Session::new();
+        let kept_id = kept.id().to_owned();
+        store.store_session(kept).await?;
+
+        let flush = task::spawn({
+            let store = store.clone();
+            async move { store.flush().await }
+        });
+        writing.recv().await.unwrap();
+        store.clear_prefix("app.").await?;
+        // the flush only writes the other session if it is the one
+        // that is kept
+        release.send(()).await.unwrap();
+        release.send(()).await.unwrap();
+        flush.await?;
+        assert_eq!(1, store.slow().inner.count().await);
+        assert!(store
+            .slow()
+            .inner
+            .load_session_by_id(&kept_id)
+            .await?
+            .is_some());
+
+        // sessions that are stored again after they were cleared are
+        // written by the next flush
+        let (store, writing, release) = gated();
+        let session = Session::new();
+        store.store_session(session.clone()).await?;
+        let flush = task::spawn({
+            let store = store.clone();
+            async move { store.flush().await }
+        });
+        writing.recv().await.unwrap();
+        store.clear_store().await?;
+        store.store_session(session.clone()).await?;
+        release.send(()).await.unwrap();
+        flush.await?;
+        assert_eq!(0, store.slow().inner.count().await);
+
+        release.send(()).await.unwrap();
+        store.flush().await?;
+        assert!(store
+            .slow()
+            .inner
+            .load_session_by_id(session.id())
+            .await?
+            .is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn destroying_principal_sessions_while_flushing() -> Result {
+        let (store, writing, release) = gated();
+        let mut session = Session::new();
+        session.set_principal("user");
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let flush = task::spawn({
+            let store = store.clone();
+            async move { store.flush().await }
+        });
+        writing.recv().await.unwrap();
+        store.destroy_principal_sessions("user").await?;
+        release.send(()).await.unwrap();
+        flush.await?;
+
+        assert_eq!(0, store.slow().inner.count().await);
+        assert!(store.state().cached_at.is_empty());
+        assert!(store.load_session(cookie_value).await?.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn invalidating_both_stores() -> Result {
+        let store = store().with_write_policy(WritePolicy::WriteBehind);
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        for _ in 0..3i8 {
+            store.store_session(Session::new()).await?;
+        }
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        store.destroy_session(session).await?;
+        assert!(store.load_session(cookie_value).await?.is_none());
+        assert_eq!(3, store.state().pending.len());
+
+        store.clear_store().await?;
+        assert_eq!(0, store.fast().count().await);
+        assert!(store.state().pending.is_empty());
+        assert!(store.state().cached_at.is_empty());
+        Ok(())
+    }
+}
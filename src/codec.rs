@@ -0,0 +1,174 @@
+use crate::{Result, SessionError};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+
+/// # A serialization format for persisted sessions
+///
+/// Session stores that serialize whole sessions, such as the
+/// [`CookieStore`](crate::CookieStore), accept a codec to choose the
+/// format that sessions are written in. This allows sharing sessions
+/// with services that are not written in Rust by picking a format
+/// that they can read. The values inside of a session are always
+/// serialized with [`serde_json`], see [`Session::insert`](crate::Session::insert).
+///
+/// Every codec has a unique [`ID`](SessionCodec::ID) that is recorded
+/// alongside the serialized session. Sessions written with any of the
+/// built-in codecs can be read by a store that is configured with a
+/// different codec, as long as the crate feature for the codec that
+/// wrote them is enabled, so the codec can be changed without logging
+/// users out.
+///
+/// The built-in codecs are:
+///
+/// | codec           | id | crate feature |
+/// |-----------------|----|---------------|
+/// | [`Bincode`]     | 1  |               |
+/// | [`Json`]        | 2  |               |
+/// | `MessagePack`   | 3  | `messagepack` |
+/// | `Cbor`          | 4  | `cbor`        |
+///
+/// Ids from 128 up to 255 are reserved for codecs that are defined
+/// outside of this crate.
+pub trait SessionCodec: Debug + Clone + Send + Sync + 'static {
+    /// the unique id of this codec
+    const ID: u8;
+
+    /// serializes a value into bytes
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+
+    /// deserializes a value from bytes written by [`SessionCodec::encode`]
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// Serializes sessions with [`bincode`](::bincode). This is the most
+/// compact of the built-in codecs, and the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Bincode;
+
+impl SessionCodec for Bincode {
+    const ID: u8 = 1;
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Serializes sessions with [`serde_json`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Json;
+
+impl SessionCodec for Json {
+    const ID: u8 = 2;
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Serializes sessions as MessagePack maps with
+/// [`rmp_serde`](::rmp_serde).
+#[cfg(feature = "messagepack")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessagePack;
+
+#[cfg(feature = "messagepack")]
+impl SessionCodec for MessagePack {
+    const ID: u8 = 3;
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(SessionError::serialization)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(bytes).map_err(SessionError::serialization)
+    }
+}
+
+/// Serializes sessions as CBOR with [`ciborium`](::ciborium).
+#[cfg(feature = "cbor")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl SessionCodec for Cbor {
+    const ID: u8 = 4;
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(value, &mut bytes).map_err(SessionError::serialization)?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        ciborium::de::from_reader(bytes).map_err(SessionError::serialization)
+    }
+}
+
+/// deserializes bytes that were written by the codec with the provided
+/// id. the configured codec is preferred, and any built-in codec is
+/// used otherwise
+pub(crate) fn decode<C: SessionCodec, T: DeserializeOwned>(
+    codec: &C,
+    id: u8,
+    bytes: &[u8],
+) -> Result<T> {
+    match id {
+        id if id == C::ID => codec.decode(bytes),
+        Bincode::ID => Bincode.decode(bytes),
+        Json::ID => Json.decode(bytes),
+        #[cfg(feature = "messagepack")]
+        MessagePack::ID => MessagePack.decode(bytes),
+        #[cfg(feature = "cbor")]
+        Cbor::ID => Cbor.decode(bytes),
+        unknown => Err(SessionError::serialization(format!(
+            "session was serialized with unknown or disabled codec {}",
+            unknown
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Session;
+
+    fn round_trip<C: SessionCodec>(codec: C) -> Result {
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        session.expire_in(std::time::Duration::from_secs(60));
+
+        let bytes = codec.encode(&session)?;
+        for decoded in [codec.decode(&bytes)?, decode(&Bincode, C::ID, &bytes)?] {
+            let decoded: Session = decoded;
+            assert_eq!(session.id(), decoded.id());
+            assert_eq!(session.expiry(), decoded.expiry());
+            assert_eq!("value", decoded.get::<String>("key").unwrap());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn round_tripping_sessions() -> Result {
+        round_trip(Bincode)?;
+        round_trip(Json)?;
+        #[cfg(feature = "messagepack")]
+        round_trip(MessagePack)?;
+        #[cfg(feature = "cbor")]
+        round_trip(Cbor)?;
+        Ok(())
+    }
+
+    #[test]
+    fn rejecting_unknown_codecs() {
+        let bytes = Bincode.encode(&Session::new()).unwrap();
+        assert!(decode::<_, Session>(&Bincode, 200, &bytes).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+use crate::{Result, SessionError};
+
+/// the bits of the envelope flags that record the compression
+/// algorithm, if any, that was applied to the payload
+pub(crate) const COMPRESSION_MASK: u8 = 0b0001_1100;
+
+const DEFLATE: u8 = 0b0000_0100;
+const ZSTD: u8 = 0b0000_1000;
+const LZ4: u8 = 0b0000_1100;
+
+/// the default size in bytes below which payloads are not compressed
+pub(crate) const DEFAULT_THRESHOLD: usize = 128;
+
+/// the size in bytes that a session kept on the server may at most
+/// decompress to
+pub(crate) const MAX_DECOMPRESSED_SIZE: usize = 16 << 20;
+
+/// # A compression algorithm for serialized sessions
+///
+/// Every algorithm is behind a cargo feature of the same name:
+/// `deflate`, `zstd` or `lz4`. Sessions compressed with an algorithm
+/// can only be loaded by a build of this crate that has the
+/// corresponding feature enabled.
+///
+/// Compressing sessions that hold secrets alongside data that an
+/// attacker can influence leaks those secrets through the length of
+/// the encrypted session, see [`CookieStore::with_compression`].
+///
+/// [`CookieStore::with_compression`]: crate::CookieStore::with_compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// deflate, as implemented by [`flate2`](::flate2)
+    #[cfg(feature = "deflate")]
+    Deflate,
+
+    /// zstandard, as implemented by [`zstd`](::zstd)
+    #[cfg(feature = "zstd")]
+    Zstd,
+
+    /// lz4, as implemented by [`lz4_flex`](::lz4_flex)
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    pub(crate) fn flag(self) -> u8 {
+        match self {
+            #[cfg(feature = "deflate")]
+            Self::Deflate => DEFLATE,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => ZSTD,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => LZ4,
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "deflate", feature = "zstd", feature = "lz4")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn compress(self, payload: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "deflate")]
+            Self::Deflate => {
+                use std::io::Write;
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder
+                    .write_all(payload)
+                    .map_err(SessionError::serialization)?;
+                encoder.finish().map_err(SessionError::serialization)
+            }
+
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::encode_all(payload, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .map_err(SessionError::serialization),
+
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(payload)),
+        }
+    }
+}
+
+/// compresses a payload unless it is smaller than the threshold, or
+/// unless compressing it does not make it any smaller. returns the
+/// flag to record in the envelope along with the resulting payload
+pub(crate) fn compress(
+    compression: Option<Compression>,
+    threshold: usize,
+    payload: Vec<u8>,
+) -> Result<(u8, Vec<u8>)> {
+    match compression {
+        Some(compression) if payload.len() >= threshold => {
+            let compressed = compression.compress(&payload)?;
+            if compressed.len() < payload.len() {
+                Ok((compression.flag(), compressed))
+            } else {
+                Ok((0, payload))
+            }
+        }
+
+        _ => Ok((0, payload)),
+    }
+}
+
+/// reverses [`compress`] according to the compression flag recorded
+/// in the envelope. payloads that would decompress to more than
+/// `limit` bytes are rejected without decompressing them any further,
+/// so that a small payload can not make us allocate an arbitrary
+/// amount of memory
+#[cfg_attr(
+    not(any(feature = "deflate", feature = "zstd", feature = "lz4")),
+    allow(unused_variables)
+)]
+pub(crate) fn decompress(flags: u8, payload: Vec<u8>, limit: usize) -> Result<Vec<u8>> {
+    match flags & COMPRESSION_MASK {
+        0 => Ok(payload),
+
+        #[cfg(feature = "deflate")]
+        DEFLATE => read_at_most(flate2::read::DeflateDecoder::new(&payload[..]), limit),
+
+        #[cfg(feature = "zstd")]
+        ZSTD => read_at_most(
+            zstd::stream::read::Decoder::new(&payload[..]).map_err(SessionError::serialization)?,
+            limit,
+        ),
+
+        #[cfg(feature = "lz4")]
+        LZ4 => {
+            // the decompressed size is prepended to the payload, and
+            // lz4_flex allocates that much up front
+            let (size, _) = lz4_flex::block::uncompressed_size(&payload)
+                .map_err(SessionError::serialization)?;
+            if size > limit {
+                return Err(exceeded(limit));
+            }
+            lz4_flex::decompress_size_prepended(&payload).map_err(SessionError::serialization)
+        }
+
+        unsupported => Err(SessionError::serialization(format!(
+            "session was compressed with {}, which is not enabled",
+            match unsupported {
+                DEFLATE => "deflate",
+                ZSTD => "zstd",
+                LZ4 => "lz4",
+                _ => "an unknown algorithm",
+            }
+        ))),
+    }
+}
+
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+fn read_at_most(decoder: impl std::io::Read, limit: usize) -> Result<Vec<u8>> {
+    use std::io::Read;
+    let mut decompressed = Vec::new();
+    decoder
+        .take((limit as u64).saturating_add(1))
+        .read_to_end(&mut decompressed)
+        .map_err(SessionError::serialization)?;
+    if decompressed.len() > limit {
+        return Err(exceeded(limit));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(any(feature = "deflate", feature = "zstd", feature = "lz4"))]
+fn exceeded(limit: usize) -> SessionError {
+    SessionError::serialization(format!("session decompresses to more than {} bytes", limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipping_small_payloads() -> Result {
+        let payload = vec![0; DEFAULT_THRESHOLD - 1];
+        let (flag, compressed) = compress(None, 0, payload.clone())?;
+        assert_eq!((0, &payload), (flag, &compressed));
+
+        for compression in all() {
+            let (flag, compressed) =
+                compress(Some(compression), DEFAULT_THRESHOLD, payload.clone())?;
+            assert_eq!((0, &payload), (flag, &compressed));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn round_tripping_payloads() -> Result {
+        let payload = b"session data ".repeat(100);
+        for compression in all() {
+            let (flag, compressed) =
+                compress(Some(compression), DEFAULT_THRESHOLD, payload.clone())?;
+            assert_eq!(flag, compression.flag());
+            assert!(compressed.len() < payload.len());
+            assert_eq!(payload, decompress(flag, compressed, payload.len())?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn limiting_decompressed_sizes() -> Result {
+        let payload = vec![0; 1 << 20];
+        for compression in all() {
+            let (flag, compressed) =
+                compress(Some(compression), DEFAULT_THRESHOLD, payload.clone())?;
+            assert!(compressed.len() < payload.len() / 100);
+            assert!(decompress(flag, compressed.clone(), payload.len() - 1).is_err());
+            assert_eq!(payload, decompress(flag, compressed, payload.len())?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejecting_unsupported_algorithms() {
+        #[cfg(not(feature = "zstd"))]
+        assert!(decompress(ZSTD, vec![], usize::MAX).is_err());
+        #[cfg(feature = "zstd")]
+        assert!(decompress(ZSTD, vec![1, 2, 3], usize::MAX).is_err());
+    }
+
+    fn all() -> Vec<Compression> {
+        vec![
+            #[cfg(feature = "deflate")]
+            Compression::Deflate,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ]
+    }
+}
@@ -1,45 +1,630 @@
-use crate::{async_trait, Result, Session, SessionStore};
+use crate::{
+    async_trait,
+    envelope::{Format, Header, ENCRYPTED, HEADER_LEN, SIGNED},
+    keyring::{Key, KEY_ID_LEN},
+    log, Bincode, Compression, Keyring, Result, Session, SessionCodec, SessionError, SessionStore,
+    UpdateMode,
+};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// the length in bytes of the authenticated prefix of a cookie: the
+/// envelope header followed by the id of the key that protects it
+const PREFIX_LEN: usize = HEADER_LEN + KEY_ID_LEN;
+
+/// the length in bytes of an HMAC-SHA256 tag
+const SIGNATURE_LEN: usize = 32;
+
+/// the length in bytes of an AES-256-GCM nonce
+const NONCE_LEN: usize = 12;
+
+/// the default maximum length of a cookie value, see
+/// [`CookieStore::with_max_size`]
+const DEFAULT_MAX_SIZE: usize = 4093;
+
+/// how many times larger than the cookies it was read from a session
+/// may at most be once it is decompressed
+const MAX_COMPRESSION_RATIO: usize = 64;
+
+/// separates the number of chunks from the data in the first chunk
+/// of a chunked session. this is not part of the base64 alphabet
+const CHUNK_COUNT_SEPARATOR: char = '.';
+
+/// How the serialized session is protected inside of the cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// the session is readable by the client, but can not be
+    /// modified without invalidating its HMAC-SHA256 signature
+    Signed,
+    /// the session is sealed with AES-256-GCM and can neither be
+    /// read nor modified by the client
+    Encrypted,
+}
+
+impl Mode {
+    fn flag(self) -> u8 {
+        match self {
+            Self::Signed => SIGNED,
+            Self::Encrypted => ENCRYPTED,
+        }
+    }
+
+    fn from_header(header: &Header) -> Option<Self> {
+        match (header.has(SIGNED), header.has(ENCRYPTED)) {
+            (true, false) => Some(Self::Signed),
+            (false, true) => Some(Self::Encrypted),
+            _ => None,
+        }
+    }
+}
 
 /// A session store that serializes the entire session into a Cookie.
 ///
 /// # ***This is not recommended for most production deployments.***
 ///
-/// This implementation uses [`bincode`](::bincode) to serialize the
-/// Session to decrease the size of the cookie. Note: There is a
+/// By default, this implementation uses [`bincode`](::bincode) to
+/// serialize the Session to decrease the size of the cookie. Other
+/// formats can be chosen with [`CookieStore::with_codec`]. Note: There is a
 /// maximum of 4093 cookie bytes allowed _per domain_, so the cookie
-/// store is limited in capacity.
+/// store is limited in capacity. To fit larger sessions, the
+/// serialized session can be compressed with
+/// [`CookieStore::with_compression`].
+///
+/// Browsers silently drop cookies that are too large, so instead of
+/// producing such a cookie, [`SessionStore::store_session`] returns
+/// [`SessionError::TooLarge`] if the cookie value would be longer than
+/// the [maximum size](CookieStore::with_max_size). Sessions that do
+/// not fit into a single cookie can instead be split across several
+/// numbered cookies with [`CookieStore::store_session_chunks`].
+///
+/// The serialized session is wrapped in a versioned envelope, which
+/// records the format version, the codec that serialized the session
+/// and how it is protected, so that later releases of this crate can
+/// still decode cookies written by this one. Cookies of an unknown
+/// version, such as those written before the envelope was introduced,
+/// are ignored rather than misread. The envelope is either signed with
+/// HMAC-SHA256 ([`CookieStore::new`]) or sealed with AES-256-GCM
+/// ([`CookieStore::encrypted`]). Cookies that were modified by the
+/// client, or that were protected with a different key, are rejected
+/// by [`SessionStore::load_session`] with [`SessionError::Tampered`].
 ///
-/// **Note:** Currently, the data in the cookie is only signed, but *not
-/// encrypted*. If the contained session data is sensitive and
-/// should not be read by a user, the cookie store is not an
-/// appropriate choice.
+/// Any of the constructors accept either a single secret key or a
+/// [`Keyring`], which allows rotating keys without invalidating
+/// existing sessions: Sessions are always stored with the primary
+/// key, and sessions that were loaded with a retired key are marked
+/// with [`Session::mark_for_reissue`]. Both kinds of cookie are
+/// accepted on load regardless of the mode the store writes, so an
+/// application can switch from signed to encrypted cookies without
+/// logging its users out.
+///
+/// **Note:** Signed cookies are *not encrypted*. If the contained
+/// session data is sensitive and should not be read by a user, use
+/// [`CookieStore::encrypted`].
 ///
 /// Expiry: `SessionStore::destroy_session` and
 /// `SessionStore::clear_store` are not meaningful for the
 /// CookieStore, and noop. Destroying a session must be done at the
 /// cookie setting level, which is outside of the scope of this crate.
+#[derive(Clone)]
+pub struct CookieStore<C = Bincode> {
+    keyring: Arc<Keyring>,
+    mode: Mode,
+    format: Format<C>,
+    max_size: usize,
+    max_chunks: usize,
+    update_mode: UpdateMode,
+}
+
+impl<C: SessionCodec> fmt::Debug for CookieStore<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookieStore")
+            .field("keyring", &self.keyring)
+            .field("mode", &self.mode)
+            .field("format", &self.format)
+            .field("max_size", &self.max_size)
+            .field("max_chunks", &self.max_chunks)
+            .field("update_mode", &self.update_mode)
+            .finish()
+    }
+}
+
+impl CookieStore<Bincode> {
+    /// constructs a new CookieStore that signs cookies with the
+    /// provided secret key or [`Keyring`]. The session data can be
+    /// read, but not modified, by the client.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a single key is provided that is shorter than 32
+    /// bytes.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::{CookieStore, Session, SessionStore};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = CookieStore::new(&[42; 32]);
+    /// let mut session = Session::new();
+    /// session.insert("user_id", 1)?;
+    /// let cookie_value = store.store_session(session).await?.unwrap();
+    /// let session = store.load_session(cookie_value).await?.unwrap();
+    /// assert_eq!(session.get::<usize>("user_id"), Some(1));
+    /// # Ok(()) }) }
+    /// ```
+    pub fn new(keyring: impl Into<Keyring>) -> Self {
+        Self {
+            keyring: Arc::new(keyring.into()),
+            mode: Mode::Signed,
+            format: Format::new(Bincode),
+            max_size: DEFAULT_MAX_SIZE,
+            max_chunks: 1,
+            update_mode: UpdateMode::default(),
+        }
+    }
+
+    /// constructs a new CookieStore that encrypts cookies with the
+    /// provided secret key or [`Keyring`], using AES-256-GCM with a
+    /// random nonce for every stored session. The session data can
+    /// neither be read nor modified by the client.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a single key is provided that is shorter than 32
+    /// bytes.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::{CookieStore, Session, SessionStore};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = CookieStore::encrypted(&[42; 32]);
+    /// let mut session = Session::new();
+    /// session.insert("email", "user@example.com")?;
+    /// let cookie_value = store.store_session(session).await?.unwrap();
+    /// assert!(!String::from_utf8_lossy(&base64::decode(&cookie_value)?).contains("example.com"));
+    /// let session = store.load_session(cookie_value).await?.unwrap();
+    /// assert_eq!(session.get::<String>("email").unwrap(), "user@example.com");
+    /// # Ok(()) }) }
+    /// ```
+    pub fn encrypted(keyring: impl Into<Keyring>) -> Self {
+        Self {
+            keyring: Arc::new(keyring.into()),
+            mode: Mode::Encrypted,
+            format: Format::new(Bincode),
+            max_size: DEFAULT_MAX_SIZE,
+            max_chunks: 1,
+            update_mode: UpdateMode::default(),
+        }
+    }
+}
+
+impl<C: SessionCodec> CookieStore<C> {
+    /// serializes sessions with the provided codec instead of
+    /// [`Bincode`]. cookies written with any of the built-in codecs
+    /// are recognized on load regardless of this setting.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::{CookieStore, Json, Session, SessionStore};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = CookieStore::new(&[42; 32]);
+    /// let json_store = store.clone().with_codec(Json);
+    ///
+    /// let mut session = Session::new();
+    /// session.insert("user_id", 1)?;
+    /// let cookie_value = json_store.store_session(session).await?.unwrap();
+    /// let session = store.load_session(cookie_value).await?.unwrap();
+    /// assert_eq!(session.get::<usize>("user_id"), Some(1));
+    /// # Ok(()) }) }
+    /// ```
+    pub fn with_codec<D: SessionCodec>(self, codec: D) -> CookieStore<D> {
+        CookieStore {
+            keyring: self.keyring,
+            mode: self.mode,
+            format: self.format.with_codec(codec),
+            max_size: self.max_size,
+            max_chunks: self.max_chunks,
+            update_mode: self.update_mode,
+        }
+    }
+
+    /// compresses serialized sessions with the provided algorithm
+    /// before they are signed or encrypted. sessions smaller than the
+    /// [compression threshold](CookieStore::with_compression_threshold),
+    /// or that would not get any smaller, are stored uncompressed.
+    ///
+    /// compressed cookies are recognized on load regardless of this
+    /// setting, as long as the crate feature for their algorithm is
+    /// enabled. sessions that would decompress to more than 64 times
+    /// the [maximum size](CookieStore::with_max_size) of all of their
+    /// chunks are rejected.
+    ///
+    /// # Security
+    ///
+    /// compression makes the length of the cookie depend on how much
+    /// of its content repeats. if a session holds a secret alongside
+    /// data that an attacker can influence, the attacker can guess
+    /// the secret piece by piece by watching whether the cookie gets
+    /// shorter, even though it is encrypted. this is the same attack
+    /// as [CRIME](https://en.wikipedia.org/wiki/CRIME) and BREACH, so
+    /// do not enable compression for such sessions.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "deflate")]
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// # use async_session::{Compression, CookieStore, Session, SessionStore};
+    /// let store = CookieStore::new(&[42; 32]);
+    /// let compressed_store = store.clone().with_compression(Compression::Deflate);
+    ///
+    /// let mut session = Session::new();
+    /// session.insert("cart", vec!["item"; 100])?;
+    /// let cookie_value = store.store_session(session.clone()).await?.unwrap();
+    /// let compressed = compressed_store.store_session(session).await?.unwrap();
+    /// assert!(compressed.len() < cookie_value.len());
+    ///
+    /// let session = store.load_session(compressed).await?.unwrap();
+    /// assert_eq!(session.get::<Vec<String>>("cart").unwrap().len(), 100);
+    /// # Ok(()) }) }
+    /// # #[cfg(not(feature = "deflate"))]
+    /// # fn main() {}
+    /// ```
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.format.compression = Some(compression);
+        self
+    }
+
+    /// sets the size in bytes of a serialized session below which it
+    /// is not compressed, because compression would not save enough
+    /// space to be worthwhile. defaults to 128 bytes.
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.format.compression_threshold = threshold;
+        self
+    }
+
+    /// sets the maximum length in bytes of a cookie value. storing a
+    /// session that does not fit returns [`SessionError::TooLarge`].
+    /// defaults to 4093 bytes.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::{CookieStore, Session, SessionError, SessionStore};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = CookieStore::new(&[42; 32]).with_max_size(100);
+    /// let mut session = Session::new();
+    /// session.insert("key", "value")?;
+    /// match store.store_session(session).await {
+    ///     Err(SessionError::TooLarge { size, max_size }) => {
+    ///         assert_eq!(max_size, 100);
+    ///         assert!(size > 100);
+    ///     }
+    ///     other => panic!("unexpected {:?}", other),
+    /// }
+    /// # Ok(()) }) }
+    /// ```
+    ///
+    /// the limit that browsers enforce includes the cookie name and
+    /// attributes, so applications with long cookie names or many
+    /// attributes may need to lower this.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// sets how sessions that were loaded from a cookie are stored.
+    /// defaults to [`UpdateMode::Replace`], which always returns a new
+    /// cookie value. the stored data of a cookie store is the cookie in
+    /// the browser, so it can not be merged with the changes of
+    /// concurrent requests. instead, with [`UpdateMode::Merge`],
+    /// [`SessionStore::store_session`] returns `None` for sessions
+    /// whose data, principal and expiry did not change, so that
+    /// requests that only read the session do not overwrite a cookie
+    /// that a concurrent request set.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::{CookieStore, Session, SessionStore, UpdateMode};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = CookieStore::new(&[42; 32]).with_update_mode(UpdateMode::Merge);
+    /// let cookie_value = store.store_session(Session::new()).await?.unwrap();
+    ///
+    /// let session = store.load_session(cookie_value.clone()).await?.unwrap();
+    /// assert_eq!(None, store.store_session(session).await?);
+    ///
+    /// let mut session = store.load_session(cookie_value).await?.unwrap();
+    /// session.insert("user_id", 1)?;
+    /// assert!(store.store_session(session).await?.is_some());
+    /// # Ok(()) }) }
+    /// ```
+    pub fn with_update_mode(mut self, update_mode: UpdateMode) -> Self {
+        self.update_mode = update_mode;
+        self
+    }
+
+    /// allows [`CookieStore::store_session_chunks`] to split a session
+    /// across up to `max_chunks` cookies, each of which is at most the
+    /// [maximum size](CookieStore::with_max_size). by default, sessions
+    /// are never split.
+    pub fn with_chunking(mut self, max_chunks: usize) -> Self {
+        self.max_chunks = max_chunks.max(1);
+        self
+    }
+
+    /// stores a session, returning the names and values of the
+    /// cookies to set for it. if the session fits into a single
+    /// cookie, this returns a single cookie named `name.0`.
+    /// otherwise, the session is split into cookies named `name.0`,
+    /// `name.1` and so on, up to the limit configured with
+    /// [`CookieStore::with_chunking`]. returns
+    /// [`SessionError::TooLarge`] if the session does not fit into that many cookies.
+    ///
+    /// the caller should remove any other cookies named `name.N`
+    /// that the client still has from a previous, larger session,
+    /// although they are ignored by
+    /// [`CookieStore::load_session_chunks`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::{CookieStore, Session};
+    /// # fn main() -> async_session::Result {
+    /// let store = CookieStore::new(&[42; 32]).with_max_size(200).with_chunking(8);
+    /// let mut session = Session::new();
+    /// session.insert("cart", vec!["item"; 50])?;
+    ///
+    /// let cookies = store.store_session_chunks("session", session)?;
+    /// assert!(cookies.len() > 1);
+    /// assert_eq!(cookies[1].0, "session.1");
+    /// assert!(cookies.iter().all(|(_, value)| value.len() <= 200));
+    ///
+    /// let session = store.load_session_chunks("session", cookies)?.unwrap();
+    /// assert_eq!(session.get::<Vec<String>>("cart").unwrap().len(), 50);
+    /// # Ok(()) }
+    /// ```
+    pub fn store_session_chunks(
+        &self,
+        name: &str,
+        mut session: Session,
+    ) -> Result<Vec<(String, String)>> {
+        session.increment_version();
+        let value = self.encode(&session)?;
+
+        // the first chunk is prefixed with the number of chunks
+        let count = (1..=self.max_chunks)
+            .find(|count| {
+                let prefix = format!("{}{}", count, CHUNK_COUNT_SEPARATOR);
+                prefix.len() + value.len() <= count.saturating_mul(self.max_size)
+            })
+            .ok_or(SessionError::TooLarge {
+                size: value.len(),
+                max_size: self.max_size.saturating_mul(self.max_chunks),
+            })?;
+
+        let mut data = format!("{}{}{}", count, CHUNK_COUNT_SEPARATOR, value);
+        let mut cookies = Vec::with_capacity(count);
+        for index in 0..count {
+            let rest = data.split_off(data.len().min(self.max_size));
+            cookies.push((format!("{}.{}", name, index), data));
+            data = rest;
+        }
+
+        Ok(cookies)
+    }
+
+    /// loads a session that was stored with
+    /// [`CookieStore::store_session_chunks`], given all cookies of the
+    /// request as name and value pairs. cookies that are not named
+    /// `name.N` are ignored. returns `Ok(None)` if any chunk is
+    /// missing, and [`SessionError::Tampered`] if the reassembled
+    /// session fails verification.
+    pub fn load_session_chunks<I, N, V>(&self, name: &str, cookies: I) -> Result<Option<Session>>
+    where
+        I: IntoIterator<Item = (N, V)>,
+        N: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let mut chunks = BTreeMap::new();
+        for (cookie_name, value) in cookies {
+            let index = cookie_name
+                .as_ref()
+                .strip_prefix(name)
+                .and_then(|suffix| suffix.strip_prefix('.'))
+                .and_then(|index| index.parse::<usize>().ok())
+                .filter(|index| *index < self.max_chunks);
+            if let Some(index) = index {
+                chunks.insert(index, value.as_ref().to_owned());
+            }
+        }
+
+        let first = match chunks.get(&0) {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+
+        let (count, first) = match first
+            .split_once(CHUNK_COUNT_SEPARATOR)
+            .and_then(|(count, first)| Some((count.parse::<usize>().ok()?, first)))
+        {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+
+        if count > self.max_chunks {
+            log::debug!(
+                "rejecting session cookie of {} chunks, more than the maximum of {}",
+                count,
+                self.max_chunks
+            );
+            return Ok(None);
+        }
+
+        let mut value = first.to_owned();
+        for index in 1..count {
+            match chunks.get(&index) {
+                Some(chunk) => value.push_str(chunk),
+                None => {
+                    log::debug!("session cookie chunk {} of {} is missing", index, count);
+                    return Ok(None);
+                }
+            }
+        }
+
+        self.decode(&value)
+    }
+
+    fn encode(&self, session: &Session) -> Result<String> {
+        let (header, payload) = self.format.encode(session, self.mode.flag())?;
+        Ok(base64::encode(self.seal(header, &payload)?))
+    }
+
+    fn decode(&self, cookie_value: &str) -> Result<Option<Session>> {
+        let envelope = base64::decode(cookie_value)?;
+        let (header, payload, retired) = match self.open(&envelope) {
+            Some(opened) => opened,
+            None => {
+                log::debug!("rejecting session cookie that failed verification");
+                return Err(SessionError::Tampered);
+            }
+        };
+
+        let limit = self
+            .max_size
+            .saturating_mul(self.max_chunks)
+            .saturating_mul(MAX_COMPRESSION_RATIO);
+        let session = match self.format.decode_at_most(header, payload, limit)? {
+            Some(session) => session,
+            None => {
+                log::debug!(
+                    "rejecting session cookie of unknown version {}",
+                    header.version
+                );
+                return Ok(None);
+            }
+        };
+
+        if retired {
+            log::trace!("session `{}` was loaded with a retired key", session.id());
+            session.mark_for_reissue();
+        }
+        Ok(session.validate())
+    }
+
+    fn seal(&self, header: Header, payload: &[u8]) -> Result<Vec<u8>> {
+        let key = self.keyring.primary();
+        let mut envelope = header.to_bytes().to_vec();
+        envelope.extend_from_slice(&key.id());
+
+        match self.mode {
+            Mode::Signed => {
+                envelope.extend_from_slice(payload);
+                let mut mac = mac(key);
+                mac.update(&envelope);
+                envelope.extend_from_slice(&mac.finalize().into_bytes());
+            }
+
+            Mode::Encrypted => {
+                let mut nonce = [0; NONCE_LEN];
+                rand::thread_rng().fill_bytes(&mut nonce);
+                let ciphertext = cipher(key)
+                    .encrypt(
+                        Nonce::from_slice(&nonce),
+                        Payload {
+                            msg: payload,
+                            aad: &envelope,
+                        },
+                    )
+                    .map_err(|_| SessionError::serialization("unable to encrypt session"))?;
+                envelope.extend_from_slice(&nonce);
+                envelope.extend_from_slice(&ciphertext);
+            }
+        }
+
+        Ok(envelope)
+    }
+
+    /// verifies or decrypts an envelope, returning its header and the
+    /// contained payload, along with whether it was protected by a
+    /// retired key
+    fn open(&self, envelope: &[u8]) -> Option<(Header, Vec<u8>, bool)> {
+        let (header, rest) = Header::parse(envelope)?;
+        if rest.len() < KEY_ID_LEN {
+            return None;
+        }
 
-#[derive(Default, Debug, Clone, Copy)]
-pub struct CookieStore;
+        let (key_id, body) = rest.split_at(KEY_ID_LEN);
+        let (key, retired) = self.keyring.get(key_id.try_into().ok()?)?;
+        let payload = match Mode::from_header(&header)? {
+            Mode::Signed => {
+                let split = envelope.len().checked_sub(SIGNATURE_LEN)?;
+                let (signed, signature) = envelope.split_at(split);
+                let mut mac = mac(key);
+                mac.update(signed);
+                mac.verify_slice(signature).ok()?;
+                signed.get(PREFIX_LEN..)?.to_vec()
+            }
 
-impl CookieStore {
-    /// constructs a new CookieStore
-    pub fn new() -> Self {
-        Self
+            Mode::Encrypted => {
+                if body.len() < NONCE_LEN {
+                    return None;
+                }
+                let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+                cipher(key)
+                    .decrypt(
+                        Nonce::from_slice(nonce),
+                        Payload {
+                            msg: ciphertext,
+                            aad: &envelope[..PREFIX_LEN],
+                        },
+                    )
+                    .ok()?
+            }
+        };
+
+        Some((header, payload, retired))
     }
 }
 
+fn mac(key: &Key) -> HmacSha256 {
+    <HmacSha256 as Mac>::new_from_slice(key.signing()).expect("hmac accepts keys of any length")
+}
+
+fn cipher(key: &Key) -> Aes256Gcm {
+    Aes256Gcm::new(key.encryption().into())
+}
+
 #[async_trait]
-impl SessionStore for CookieStore {
+impl<C: SessionCodec> SessionStore for CookieStore<C> {
     async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
-        let serialized = base64::decode(cookie_value)?;
-        let session: Session = bincode::deserialize(&serialized)?;
-        Ok(session.validate())
+        self.decode(&cookie_value)
     }
 
-    async fn store_session(&self, session: Session) -> Result<Option<String>> {
-        let serialized = bincode::serialize(&session)?;
-        Ok(Some(base64::encode(serialized)))
+    async fn store_session(&self, mut session: Session) -> Result<Option<String>> {
+        if self.update_mode == UpdateMode::Merge && session.is_unchanged() {
+            log::trace!("leaving the cookie of unchanged session `{}`", session.id());
+            return Ok(None);
+        }
+
+        session.increment_version();
+        let value = self.encode(&session)?;
+        if value.len() > self.max_size {
+            return Err(SessionError::TooLarge {
+                size: value.len(),
+                max_size: self.max_size,
+            });
+        }
+        Ok(Some(value))
     }
 
     async fn destroy_session(&self, _session: Session) -> Result {
@@ -54,11 +639,13 @@ impl SessionStore for CookieStore {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use async_std::task;
     use std::time::Duration;
+
+    const KEY: &[u8] = &[42; 32];
+
     #[async_std::test]
     async fn creating_a_new_session_with_no_expiry() -> Result {
-        let store = CookieStore::new();
+        let store = CookieStore::new(KEY);
         let mut session = Session::new();
         session.insert("key", "Hello")?;
         let cloned = session.clone();
@@ -73,7 +660,7 @@ mod tests {
 
     #[async_std::test]
     async fn updating_a_session() -> Result {
-        let store = CookieStore::new();
+        let store = CookieStore::new(KEY);
         let mut session = Session::new();
 
         session.insert("key", "value")?;
@@ -91,23 +678,24 @@ mod tests {
 
     #[async_std::test]
     async fn updating_a_session_extending_expiry() -> Result {
-        let store = CookieStore::new();
+        let store = CookieStore::new(KEY);
         let mut session = Session::new();
         session.expire_in(Duration::from_secs(1));
-        let original_expires = session.expiry().unwrap().clone();
+        let original_expires = *session.expiry().unwrap();
         let cookie_value = store.store_session(session).await?.unwrap();
 
         let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
 
         assert_eq!(session.expiry().unwrap(), &original_expires);
         session.expire_in(Duration::from_secs(3));
-        let new_expires = session.expiry().unwrap().clone();
+        let new_expires = *session.expiry().unwrap();
         let cookie_value = store.store_session(session).await?.unwrap();
 
-        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        let mut session = store.load_session(cookie_value).await?.unwrap();
         assert_eq!(session.expiry().unwrap(), &new_expires);
 
-        task::sleep(Duration::from_secs(3)).await;
+        session.set_expiry(time::OffsetDateTime::now_utc() - Duration::from_secs(1));
+        let cookie_value = store.store_session(session).await?.unwrap();
         assert_eq!(None, store.load_session(cookie_value).await?);
 
         Ok(())
@@ -115,7 +703,7 @@ mod tests {
 
     #[async_std::test]
     async fn creating_a_new_session_with_expiry() -> Result {
-        let store = CookieStore::new();
+        let store = CookieStore::new(KEY);
         let mut session = Session::new();
         session.expire_in(Duration::from_secs(3));
         session.insert("key", "value")?;
@@ -129,9 +717,276 @@ mod tests {
 
         assert!(!loaded_session.is_expired());
 
-        task::sleep(Duration::from_secs(3)).await;
+        let mut expired = Session::new();
+        expired.set_expiry(time::OffsetDateTime::now_utc() - Duration::from_secs(1));
+        let cookie_value = store.store_session(expired).await?.unwrap();
         assert_eq!(None, store.load_session(cookie_value).await?);
 
         Ok(())
     }
+
+    #[async_std::test]
+    async fn rejecting_a_tampered_session() -> Result {
+        let store = CookieStore::new(KEY);
+        let mut session = Session::new();
+        session.insert("admin", false)?;
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let mut signed = base64::decode(cookie_value)?;
+        let payload_len = signed.len() - SIGNATURE_LEN;
+        let position = signed[PREFIX_LEN..payload_len]
+            .windows(5)
+            .position(|window| window == b"false")
+            .unwrap()
+            + PREFIX_LEN;
+        signed[position..position + 5].copy_from_slice(b"true ");
+
+        let result = store.load_session(base64::encode(signed)).await;
+        assert!(matches!(result, Err(SessionError::Tampered)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejecting_a_session_signed_with_another_key() -> Result {
+        let store = CookieStore::new(KEY);
+        let other_store = CookieStore::new(&[7; 32]);
+        let cookie_value = other_store.store_session(Session::new()).await?.unwrap();
+        for cookie_value in [cookie_value, base64::encode([0; 8])] {
+            let result = store.load_session(cookie_value).await;
+            assert!(matches!(result, Err(SessionError::Tampered)));
+        }
+        assert!(matches!(
+            store.load_session("not base64".into()).await,
+            Err(SessionError::InvalidCookie(_))
+        ));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn round_tripping_an_encrypted_session() -> Result {
+        let store = CookieStore::encrypted(KEY);
+        let mut session = Session::new();
+        session.insert("secret", "swordfish")?;
+        let id = session.id().to_owned();
+        let cookie_value = store.store_session(session.clone()).await?.unwrap();
+
+        let envelope = base64::decode(&cookie_value)?;
+        let (header, _) = Header::parse(&envelope).unwrap();
+        assert_eq!(header, Header::new(Bincode::ID, ENCRYPTED));
+        assert!(!envelope.windows(9).any(|window| window == b"swordfish"));
+        assert_ne!(cookie_value, store.store_session(session).await?.unwrap());
+
+        let loaded_session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(id, loaded_session.id());
+        assert_eq!("swordfish", loaded_session.get::<String>("secret").unwrap());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejecting_a_tampered_encrypted_session() -> Result {
+        let store = CookieStore::encrypted(KEY);
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        let mut envelope = base64::decode(cookie_value)?;
+        let last = envelope.len() - 1;
+        envelope[last] ^= 1;
+        let result = store.load_session(base64::encode(&envelope)).await;
+        assert!(matches!(result, Err(SessionError::Tampered)));
+
+        envelope[last] ^= 1;
+        envelope[2] = SIGNED;
+        let result = store.load_session(base64::encode(&envelope)).await;
+        assert!(matches!(result, Err(SessionError::Tampered)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn loading_signed_sessions_in_an_encrypted_store() -> Result {
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let cookie_value = CookieStore::new(KEY).store_session(session).await?.unwrap();
+        let session = CookieStore::encrypted(KEY)
+            .load_session(cookie_value)
+            .await?
+            .unwrap();
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn ignoring_sessions_of_an_unknown_version() -> Result {
+        let store = CookieStore::new(KEY);
+        let (header, payload) = store.format.encode(&Session::new(), SIGNED)?;
+        let header = Header {
+            version: crate::envelope::VERSION + 1,
+            ..header
+        };
+        let cookie_value = base64::encode(store.seal(header, &payload)?);
+        assert_eq!(None, store.load_session(cookie_value).await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rotating_keys() -> Result {
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let old_signed = CookieStore::new(KEY)
+            .store_session(session.clone())
+            .await?
+            .unwrap();
+        let old_encrypted = CookieStore::encrypted(KEY)
+            .store_session(session.clone())
+            .await?
+            .unwrap();
+
+        let keyring = Keyring::new(&[9; 32]).with_retired(KEY);
+        for store in [
+            CookieStore::new(keyring.clone()),
+            CookieStore::encrypted(keyring),
+        ] {
+            for cookie_value in [&old_signed, &old_encrypted] {
+                let loaded = store.load_session(cookie_value.clone()).await?.unwrap();
+                assert!(loaded.should_reissue());
+                assert!(loaded.data_changed());
+                assert_eq!("value", loaded.get::<String>("key").unwrap());
+
+                let reissued = store.store_session(loaded).await?.unwrap();
+                let reloaded = store.load_session(reissued).await?.unwrap();
+                assert!(!reloaded.should_reissue());
+                assert!(!reloaded.data_changed());
+            }
+        }
+
+        let rotated_out = CookieStore::new(&[9; 32]);
+        for cookie_value in [old_signed, old_encrypted] {
+            let result = rotated_out.load_session(cookie_value).await;
+            assert!(matches!(result, Err(SessionError::Tampered)));
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn refusing_oversized_sessions() -> Result {
+        let store = CookieStore::new(KEY);
+        let mut session = Session::new();
+        session.insert("blob", "x".repeat(DEFAULT_MAX_SIZE))?;
+        let result = store.store_session(session.clone()).await;
+        assert!(matches!(
+            result,
+            Err(SessionError::TooLarge {
+                max_size: DEFAULT_MAX_SIZE,
+                ..
+            })
+        ));
+
+        let result = store.store_session_chunks("session", session);
+        assert!(matches!(result, Err(SessionError::TooLarge { .. })));
+        Ok(())
+    }
+
+    #[cfg(feature = "deflate")]
+    #[async_std::test]
+    async fn refusing_sessions_that_decompress_too_far() -> Result {
+        let store = CookieStore::new(KEY).with_compression(Compression::Deflate);
+        let mut session = Session::new();
+        session.insert("blob", "x".repeat(DEFAULT_MAX_SIZE * MAX_COMPRESSION_RATIO))?;
+        let cookie_value = store.store_session(session).await?.unwrap();
+        assert!(cookie_value.len() < DEFAULT_MAX_SIZE);
+
+        assert!(store.load_session(cookie_value.clone()).await.is_err());
+        let store = store.with_chunking(2);
+        assert!(store.load_session(cookie_value).await?.is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn chunking_large_sessions() -> Result {
+        let store = CookieStore::encrypted(KEY)
+            .with_max_size(1000)
+            .with_chunking(4);
+        let mut session = Session::new();
+        session.insert("blob", "x".repeat(2000))?;
+
+        let cookies = store.store_session_chunks("session", session.clone())?;
+        let names: Vec<_> = cookies.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["session.0", "session.1", "session.2"]);
+        assert!(cookies.iter().all(|(_, value)| value.len() <= 1000));
+
+        let mut request_cookies = cookies.clone();
+        request_cookies.push(("session.3".into(), "stale".into()));
+        request_cookies.push(("other".into(), "cookie".into()));
+        request_cookies.reverse();
+        let loaded = store
+            .load_session_chunks("session", request_cookies)?
+            .unwrap();
+        assert_eq!(session.id(), loaded.id());
+
+        let missing_chunk = cookies.into_iter().filter(|(name, _)| name != "session.1");
+        assert_eq!(None, store.load_session_chunks("session", missing_chunk)?);
+
+        session.insert("blob", "x".repeat(5000))?;
+        assert!(store.store_session_chunks("session", session).is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn ignoring_sessions_of_too_many_chunks() -> Result {
+        let store = CookieStore::new(KEY).with_max_size(1000).with_chunking(4);
+        let mut session = Session::new();
+        session.insert("blob", "x".repeat(2000))?;
+        let cookies = store.store_session_chunks("session", session)?;
+        assert_eq!(cookies.len(), 3);
+
+        let store = store.with_chunking(2);
+        assert_eq!(None, store.load_session_chunks("session", cookies)?);
+
+        let forged = vec![("session.0", format!("{}.x", usize::MAX))];
+        assert_eq!(None, store.load_session_chunks("session", forged)?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn chunking_small_sessions() -> Result {
+        let store = CookieStore::new(KEY).with_chunking(4);
+        let cookies = store.store_session_chunks("session", Session::new())?;
+        assert_eq!(cookies.len(), 1);
+        assert!(store.load_session_chunks("session", cookies)?.is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn leaving_unchanged_sessions_in_merge_mode() -> Result {
+        let store = CookieStore::new(KEY).with_update_mode(UpdateMode::Merge);
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(None, store.store_session(session).await?);
+
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        session.expire_in(Duration::from_secs(60));
+        assert!(store.store_session(session).await?.is_some());
+
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        session.regenerate();
+        assert!(store.store_session(session).await?.is_some());
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        session.mark_for_reissue();
+        assert!(store.store_session(session).await?.is_some());
+
+        let session = CookieStore::new(KEY)
+            .load_session(cookie_value)
+            .await?
+            .unwrap();
+        assert!(CookieStore::new(KEY)
+            .store_session(session)
+            .await?
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic]
+    fn refusing_a_short_key() {
+        CookieStore::new(&[1; 16]);
+    }
 }
@@ -0,0 +1,459 @@
+use crate::{
+    async_trait,
+    keyring::{Key, KEY_ID_LEN},
+    log, Keyring, Result, Session, SessionError, SessionInfo, SessionPage, SessionStore,
+};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use time::OffsetDateTime as DateTime;
+
+/// the key that the sealed session data is stored under in the
+/// session that is handed to the inner store
+const SEALED_KEY: &str = "__async_session_sealed";
+
+/// the version of the sealed data format
+const VERSION: u8 = 1;
+
+/// the length in bytes of an AES-256-GCM nonce
+const NONCE_LEN: usize = 12;
+
+/// the length in bytes of the version and key id that precede the
+/// nonce and ciphertext
+const PREFIX_LEN: usize = 1 + KEY_ID_LEN;
+
+/// prepended to the session id to form the associated data, so that
+/// sealed data can only be opened as the session it was sealed for
+const AAD_CONTEXT: &[u8] = b"async-session sealed data\0";
+
+/// # A session store that encrypts session data at rest
+///
+/// Wraps any other session store, so that the data of every session
+/// is encrypted with AES-256-GCM before it is handed to that store,
+/// and decrypted again when it is loaded. The inner store only ever
+/// sees a session with the same id and expiry, whose data is a single
+/// opaque entry, so that it can still look sessions up and clean up
+/// expired ones while database dumps and backups contain no session
+/// data. The principal of a session is not encrypted either, so that
+/// the inner store can find the sessions of a principal.
+///
+/// The principal and expiry are sealed along with the data, and
+/// loaded sessions take them from the sealed data rather than from
+/// the inner store, so changing them in the inner store does not
+/// change whom a session belongs to or extend its lifetime.
+///
+/// Sessions are encrypted with the primary key of a [`Keyring`], and
+/// sessions that were encrypted with a retired key are still loaded,
+/// but are marked with
+/// [`Session::mark_for_reissue`](crate::Session::mark_for_reissue) so
+/// that they are encrypted with the primary key the next time they
+/// are stored.
+///
+/// The sealed data is bound to the session id, so it can not be
+/// moved to another session. Sessions whose data fails decryption,
+/// that were encrypted with a key that is no longer part of the
+/// keyring, or that are not encrypted at all are rejected with
+/// [`SessionError::Tampered`]. Sessions that were stored before the
+/// store was wrapped can be migrated with
+/// [`EncryptedStore::accept_unencrypted`].
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{EncryptedStore, MemoryStore, Session, SessionStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// let store = EncryptedStore::new(MemoryStore::new(), &[42; 32]);
+/// let mut session = Session::new();
+/// session.insert("user_id", 1)?;
+/// let cookie_value = store.store_session(session).await?.unwrap();
+///
+/// let sealed = store.inner().load_session(cookie_value.clone()).await?.unwrap();
+/// assert_eq!(sealed.get::<usize>("user_id"), None);
+///
+/// let session = store.load_session(cookie_value).await?.unwrap();
+/// assert_eq!(session.get::<usize>("user_id"), Some(1));
+/// # Ok(()) }) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct EncryptedStore<S> {
+    inner: S,
+    keyring: Arc<Keyring>,
+    accept_unencrypted: bool,
+}
+
+impl<S: SessionStore + Send + Sync> EncryptedStore<S> {
+    /// constructs a new EncryptedStore that encrypts session data
+    /// with the provided secret key or [`Keyring`] before storing it
+    /// in the inner store
+    ///
+    /// # Panics
+    ///
+    /// Panics if a single key is provided that is shorter than 32
+    /// bytes.
+    pub fn new(inner: S, keyring: impl Into<Keyring>) -> Self {
+        Self {
+            inner,
+            keyring: Arc::new(keyring.into()),
+            accept_unencrypted: false,
+        }
+    }
+
+    /// loads sessions that were stored unencrypted, for example
+    /// before the inner store was wrapped, instead of rejecting them.
+    /// they are marked with
+    /// [`Session::mark_for_reissue`](crate::Session::mark_for_reissue)
+    /// so that they are encrypted the next time they are stored.
+    ///
+    /// anyone who can write to the inner store can then hand this
+    /// store arbitrary session data, so this should only be enabled
+    /// while existing sessions are migrated.
+    pub fn accept_unencrypted(mut self) -> Self {
+        self.accept_unencrypted = true;
+        self
+    }
+
+    /// the store that the encrypted sessions are stored in
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// encrypts the session data with the primary key
+    fn seal(&self, id: &str, sealed_data: &Sealed) -> Result<String> {
+        let key = self.keyring.primary();
+        let mut sealed = vec![VERSION];
+        sealed.extend_from_slice(&key.id());
+
+        let plaintext = bincode::serialize(sealed_data)?;
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = cipher(key)
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad(&sealed, id),
+                },
+            )
+            .map_err(|_| SessionError::serialization("unable to encrypt session"))?;
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(base64::encode(sealed))
+    }
+
+    /// replaces the session data with its sealed form, which is
+    /// marked as changed so that it is written as a whole even by a
+    /// store that [merges](crate::UpdateMode::Merge) sessions
+    fn seal_session(&self, session: Session) -> Result<Session> {
+        let sealed = self.seal(
+            session.id(),
+            &Sealed {
+                data: session.data(),
+                principal: session.principal().map(str::to_owned),
+                expiry: session.expiry().copied(),
+            },
+        )?;
+        let mut session = session.with_data(HashMap::new());
+        session.insert_raw(SEALED_KEY, sealed);
+        Ok(session)
+    }
+
+    /// decrypts sealed session data, returning it along with whether
+    /// it was encrypted with a retired key
+    fn open(&self, id: &str, sealed: &str) -> Option<(Sealed, bool)> {
+        let sealed = base64::decode(sealed).ok()?;
+        if sealed.len() < PREFIX_LEN + NONCE_LEN || sealed[0] != VERSION {
+            return None;
+        }
+
+        let (prefix, body) = sealed.split_at(PREFIX_LEN);
+        let (key, retired) = self.keyring.get(prefix[1..].try_into().ok()?)?;
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        let plaintext = cipher(key)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad(prefix, id),
+                },
+            )
+            .ok()?;
+        Some((bincode::deserialize(&plaintext).ok()?, retired))
+    }
+
+    /// replaces the sealed data of a session that was loaded from the
+    /// inner store with the decrypted data, principal and expiry.
+    /// returns None if the session is expired according to the latter
+    fn open_session(&self, session: Session) -> Result<Option<Session>> {
+        let sealed = match session.get_raw(SEALED_KEY) {
+            Some(sealed) => sealed,
+            None if self.accept_unencrypted => {
+                log::trace!("session `{}` was stored unencrypted", session.id());
+                session.mark_for_reissue();
+                return Ok(Some(session));
+            }
+
+            None => {
+                log::debug!("rejecting unencrypted session `{}`", session.id());
+                return Err(SessionError::Tampered);
+            }
+        };
+
+        let (sealed, retired) = match self.open(session.id(), &sealed) {
+            Some(opened) => opened,
+            None => {
+                log::debug!(
+                    "rejecting session `{}` that failed decryption",
+                    session.id()
+                );
+                return Err(SessionError::Tampered);
+            }
+        };
+
+        if retired {
+            log::trace!("session `{}` was loaded with a retired key", session.id());
+            session.mark_for_reissue();
+        }
+        Ok(session
+            .with_data(sealed.data)
+            .with_principal(sealed.principal)
+            .with_expiry(sealed.expiry)
+            .validate())
+    }
+}
+
+/// what is encrypted: everything about a session that the inner
+/// store does not need to read
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    data: HashMap<String, String>,
+    principal: Option<String>,
+    expiry: Option<DateTime>,
+}
+
+fn cipher(key: &Key) -> Aes256Gcm {
+    Aes256Gcm::new(key.storage().into())
+}
+
+fn aad(prefix: &[u8], id: &str) -> Vec<u8> {
+    [AAD_CONTEXT, prefix, id.as_bytes()].concat()
+}
+
+#[async_trait]
+impl<S: SessionStore + Send + Sync> SessionStore for EncryptedStore<S> {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        match self.inner.load_session(cookie_value).await? {
+            Some(session) => self.open_session(session),
+            None => Ok(None),
+        }
+    }
+
+    async fn load_session_by_id(&self, id: &str) -> Result<Option<Session>> {
+        match self.inner.load_session_by_id(id).await? {
+            Some(session) => self.open_session(session),
+            None => Ok(None),
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        self.inner.store_session(self.seal_session(session)?).await
+    }
+
+    async fn compare_and_store(&self, session: Session) -> Result<Option<String>> {
+        self.inner
+            .compare_and_store(self.seal_session(session)?)
+            .await
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        self.inner.destroy_session(session).await
+    }
+
+    async fn clear_store(&self) -> Result {
+        self.inner.clear_store().await
+    }
+
+    async fn clear_prefix(&self, prefix: &str) -> Result {
+        self.inner.clear_prefix(prefix).await
+    }
+
+    async fn list_sessions(&self, cursor: Option<&str>, limit: usize) -> Result<SessionPage> {
+        self.inner.list_sessions(cursor, limit).await
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<SessionPage> {
+        self.inner.list_prefix(prefix, cursor, limit).await
+    }
+
+    async fn list_principal_sessions(&self, principal: &str) -> Result<Vec<SessionInfo>> {
+        self.inner.list_principal_sessions(principal).await
+    }
+
+    async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+        self.inner.destroy_principal_sessions(principal).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileStore, MemoryStore};
+    use std::{fs, time::Duration};
+
+    fn store() -> EncryptedStore<MemoryStore> {
+        EncryptedStore::new(MemoryStore::new(), &[1; 32])
+    }
+
+    #[async_std::test]
+    async fn storing_and_loading_a_session() -> Result {
+        let store = store();
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        session.expire_in(Duration::from_secs(60));
+        let (id, expiry) = (session.id().to_owned(), *session.expiry().unwrap());
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let sealed = store
+            .inner()
+            .load_session(cookie_value.clone())
+            .await?
+            .unwrap();
+        assert_eq!(id, sealed.id());
+        assert_eq!(Some(&expiry), sealed.expiry());
+        assert_eq!(1, sealed.len());
+        assert!(sealed.get_raw("key").is_none());
+
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(id, session.id());
+        assert_eq!(Some(&expiry), session.expiry());
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        assert!(!session.data_changed());
+
+        session.insert("key", "other value")?;
+        assert_eq!(None, store.store_session(session).await?);
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!("other value", session.get::<String>("key").unwrap());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn no_plaintext_at_rest() -> Result {
+        let dir = tempfile::tempdir().unwrap();
+        let store = EncryptedStore::new(FileStore::new(dir.path()), &[1; 32]);
+        let mut session = Session::new();
+        session.insert("secret", "hunter2")?;
+        store.store_session(session).await?;
+
+        let files: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .flat_map(|shard| fs::read_dir(shard.unwrap().path()).unwrap())
+            .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+            .collect();
+        assert_eq!(1, files.len());
+        assert!(!String::from_utf8_lossy(&files[0]).contains("hunter2"));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rotating_keys() -> Result {
+        let old_store = store();
+        let cookie_value = old_store.store_session(Session::new()).await?.unwrap();
+        let inner = old_store.inner().clone();
+
+        let store =
+            EncryptedStore::new(inner.clone(), Keyring::new(&[2; 32]).with_retired(&[1; 32]));
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert!(session.should_reissue());
+        store.store_session(session).await?;
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert!(!session.should_reissue());
+
+        let store = EncryptedStore::new(inner, &[3; 32]);
+        let result = store.load_session(cookie_value).await;
+        assert!(matches!(result, Err(SessionError::Tampered)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejecting_tampered_sessions() -> Result {
+        let store = store();
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        let other = store.store_session(Session::new()).await?.unwrap();
+
+        let sealed = store.inner().load_session(other).await?.unwrap();
+        let mut session = store
+            .inner()
+            .load_session(cookie_value.clone())
+            .await?
+            .unwrap();
+        session.insert_raw(SEALED_KEY, sealed.get_raw(SEALED_KEY).unwrap());
+        store.inner().store_session(session).await?;
+
+        let result = store.load_session(cookie_value).await;
+        assert!(matches!(result, Err(SessionError::Tampered)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn ignoring_tampered_principals_and_expiries() -> Result {
+        let store = store();
+        let mut session = Session::new();
+        session.set_principal("user-1");
+        session.expire_in(Duration::from_secs(60));
+        let expiry = *session.expiry().unwrap();
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let mut session = store
+            .inner()
+            .load_session(cookie_value.clone())
+            .await?
+            .unwrap();
+        session.set_principal("user-2");
+        session.expire_in(Duration::from_secs(60 * 60));
+        store.inner().store_session(session).await?;
+
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(Some("user-1"), session.principal());
+        assert_eq!(Some(&expiry), session.expiry());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn comparing_versions_before_storing() -> Result {
+        let store = store();
+        let cookie_value = store.compare_and_store(Session::new()).await?.unwrap();
+        let stale = store.load_session(cookie_value.clone()).await?.unwrap();
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        store.compare_and_store(session).await?;
+
+        let result = store.compare_and_store(stale).await;
+        assert!(matches!(result, Err(SessionError::Conflict)));
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(2, session.version());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn loading_unencrypted_sessions() -> Result {
+        let store = store();
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let cookie_value = store.inner().store_session(session).await?.unwrap();
+        let result = store.load_session(cookie_value.clone()).await;
+        assert!(matches!(result, Err(SessionError::Tampered)));
+
+        let store = store.accept_unencrypted();
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        assert!(session.should_reissue());
+        Ok(())
+    }
+}
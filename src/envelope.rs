@@ -0,0 +1,270 @@
+use crate::{
+    codec::{self, Bincode, SessionCodec},
+    compression::{self, Compression},
+    Result, Session, SessionError,
+};
+use std::marker::PhantomData;
+
+/// The current version of the envelope format.
+///
+/// The version describes how the payload of an envelope is laid out,
+/// which includes the serialized layout of [`Session`]. Whenever that
+/// layout changes, this must be bumped and a decoder for the previous
+/// version must be kept in [`Registry::DECODERS`], so that sessions
+/// stored by an earlier release of this crate can still be loaded.
+pub(crate) const VERSION: u8 = 1;
+
+/// the length in bytes of an envelope header. this is the same for
+/// every version, so that the version can always be read
+pub(crate) const HEADER_LEN: usize = 3;
+
+/// flag set on envelopes whose body is signed with HMAC-SHA256
+pub(crate) const SIGNED: u8 = 0b0000_0001;
+
+/// flag set on envelopes whose body is encrypted with AES-256-GCM
+pub(crate) const ENCRYPTED: u8 = 0b0000_0010;
+
+/// decodes a session payload of one envelope version, given the
+/// configured codec and the id of the codec the payload was
+/// serialized with
+type Decoder<C> = fn(&C, u8, &[u8]) -> Result<Session>;
+
+/// Registry of payload decoders, keyed by envelope version.
+///
+/// Version 1 is the first released layout, so there are no decoders
+/// for earlier versions yet.
+struct Registry<C>(PhantomData<C>);
+
+impl<C: SessionCodec> Registry<C> {
+    const DECODERS: &'static [(u8, Decoder<C>)] = &[(1, decode_v1::<C>)];
+
+    fn get(version: u8) -> Option<Decoder<C>> {
+        Self::DECODERS
+            .iter()
+            .find(|(decoder_version, _)| *decoder_version == version)
+            .map(|(_, decoder)| *decoder)
+    }
+}
+
+/// The fixed-size header that precedes every serialized session:
+///
+/// | byte | contents                                                |
+/// |------|---------------------------------------------------------|
+/// | 0    | format version, see [`VERSION`]                         |
+/// | 1    | id of the codec the session was serialized with         |
+/// | 2    | flags describing how the body is protected and compressed |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub(crate) version: u8,
+    pub(crate) codec: u8,
+    pub(crate) flags: u8,
+}
+
+impl Header {
+    /// a header for a session serialized in the current version
+    pub(crate) fn new(codec: u8, flags: u8) -> Self {
+        Self {
+            version: VERSION,
+            codec,
+            flags,
+        }
+    }
+
+    pub(crate) fn has(&self, flag: u8) -> bool {
+        self.flags & flag == flag
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; HEADER_LEN] {
+        [self.version, self.codec, self.flags]
+    }
+
+    /// splits an envelope into its header and the remaining bytes
+    pub(crate) fn parse(envelope: &[u8]) -> Option<(Self, &[u8])> {
+        if envelope.len() < HEADER_LEN {
+            return None;
+        }
+
+        let (header, rest) = envelope.split_at(HEADER_LEN);
+        let header = Self {
+            version: header[0],
+            codec: header[1],
+            flags: header[2],
+        };
+        Some((header, rest))
+    }
+}
+
+/// How sessions are turned into envelope payloads.
+#[derive(Debug, Clone)]
+pub(crate) struct Format<C = Bincode> {
+    pub(crate) codec: C,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) compression_threshold: usize,
+}
+
+impl<C: SessionCodec> Format<C> {
+    pub(crate) fn new(codec: C) -> Self {
+        Self {
+            codec,
+            compression: None,
+            compression_threshold: compression::DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// the same format, serializing with a different codec
+    pub(crate) fn with_codec<D: SessionCodec>(self, codec: D) -> Format<D> {
+        Format {
+            codec,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+        }
+    }
+
+    /// serializes a session into a payload of the current version,
+    /// returning it along with its header. the provided flags are
+    /// recorded in the header in addition to any compression flag
+    pub(crate) fn encode(&self, session: &Session, flags: u8) -> Result<(Header, Vec<u8>)> {
+        let payload = self.codec.encode(session)?;
+        let (compression_flag, payload) =
+            compression::compress(self.compression, self.compression_threshold, payload)?;
+        Ok((Header::new(C::ID, flags | compression_flag), payload))
+    }
+
+    /// deserializes a payload with the decoder registered for the
+    /// version in its header, after reversing any compression.
+    /// returns None if the version is unknown, for example because
+    /// the envelope was written by a newer release of this crate
+    pub(crate) fn decode(&self, header: Header, payload: Vec<u8>) -> Result<Option<Session>> {
+        self.decode_at_most(header, payload, compression::MAX_DECOMPRESSED_SIZE)
+    }
+
+    /// like [`Format::decode`], but rejects payloads that decompress
+    /// to more than `limit` bytes
+    pub(crate) fn decode_at_most(
+        &self,
+        header: Header,
+        payload: Vec<u8>,
+        limit: usize,
+    ) -> Result<Option<Session>> {
+        let decoder = match Registry::<C>::get(header.version) {
+            Some(decoder) => decoder,
+            None => return Ok(None),
+        };
+
+        let payload = compression::decompress(header.flags, payload, limit)?;
+        Ok(Some(decoder(&self.codec, header.codec, &payload)?))
+    }
+
+    /// serializes a session into a record for a store that keeps
+    /// sessions on the server, and therefore neither signs nor
+    /// encrypts them: the header followed by the payload
+    pub(crate) fn encode_record(&self, session: &Session) -> Result<Vec<u8>> {
+        let (header, payload) = self.encode(session, 0)?;
+        let mut record = header.to_bytes().to_vec();
+        record.extend(payload);
+        Ok(record)
+    }
+
+    /// the version of the session in a stored record, which is 0 if
+    /// there is no record or it can not be decoded
+    pub(crate) fn record_version(&self, record: Option<&[u8]>) -> u64 {
+        record
+            .and_then(|record| self.decode_record(record).ok().flatten())
+            .map_or(0, |session| session.version())
+    }
+
+    /// reverses [`Format::encode_record`]. returns None if the record
+    /// was written in an unknown version
+    pub(crate) fn decode_record(&self, record: &[u8]) -> Result<Option<Session>> {
+        let (header, payload) = Header::parse(record)
+            .ok_or_else(|| SessionError::serialization("truncated session record"))?;
+        self.decode(header, payload.to_vec())
+    }
+}
+
+fn decode_v1<C: SessionCodec>(codec: &C, id: u8, payload: &[u8]) -> Result<Session> {
+    codec::decode(codec, id, payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_tripping_a_header() {
+        let header = Header::new(Bincode::ID, SIGNED);
+        let mut envelope = header.to_bytes().to_vec();
+        envelope.extend_from_slice(b"body");
+
+        let (parsed, body) = Header::parse(&envelope).unwrap();
+        assert_eq!(parsed, header);
+        assert!(parsed.has(SIGNED));
+        assert!(!parsed.has(ENCRYPTED));
+        assert_eq!(body, b"body");
+        assert_eq!(None, Header::parse(&envelope[..2]));
+    }
+
+    #[test]
+    fn decoding_the_current_version() -> Result {
+        assert!(Registry::<Bincode>::get(VERSION).is_some());
+
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        session.set_principal("user");
+        session.increment_version();
+        let format = Format::new(Bincode);
+        let (header, payload) = format.encode(&session, SIGNED)?;
+        assert_eq!(header, Header::new(Bincode::ID, SIGNED));
+        let decoded = format.decode(header, payload)?.unwrap();
+        assert_eq!(session.id(), decoded.id());
+        assert_eq!("value", decoded.get::<String>("key").unwrap());
+        assert_eq!(Some("user"), decoded.principal());
+        assert_eq!(session.created(), decoded.created());
+        assert_eq!(1, decoded.version());
+        Ok(())
+    }
+
+    #[test]
+    fn skipping_unknown_versions_and_codecs() -> Result {
+        let format = Format::new(Bincode);
+        let (header, payload) = format.encode(&Session::new(), 0)?;
+        let newer = Header {
+            version: VERSION + 1,
+            ..header
+        };
+        assert_eq!(None, format.decode(newer, payload.clone())?);
+
+        let unknown_codec = Header { codec: 0, ..header };
+        assert!(format.decode(unknown_codec, payload).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decoding_with_a_different_codec() -> Result {
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let (header, payload) = Format::new(crate::Json).encode(&session, 0)?;
+        let decoded = Format::new(Bincode).decode(header, payload)?.unwrap();
+        assert_eq!("value", decoded.get::<String>("key").unwrap());
+        Ok(())
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn compressing_large_sessions() -> Result {
+        let format = Format {
+            compression: Some(Compression::Deflate),
+            ..Format::new(Bincode)
+        };
+
+        let (header, _) = format.encode(&Session::new(), 0)?;
+        assert_eq!(header.flags & compression::COMPRESSION_MASK, 0);
+
+        let mut session = Session::new();
+        session.insert("cart", vec!["item"; 200])?;
+        let (header, payload) = format.encode(&session, SIGNED)?;
+        assert!(header.has(SIGNED | Compression::Deflate.flag()));
+        assert_eq!(format.decode(header, payload)?.unwrap().len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use std::{error::Error as StdError, fmt};
+
+/// a boxed error from a third-party crate
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// # Errors returned by this crate and by session stores
+///
+/// The variants distinguish failures that are caused by the client,
+/// such as a malformed or modified cookie, from failures of the
+/// application or its storage backend. Session middleware will
+/// typically start a fresh session for [`SessionError::InvalidCookie`],
+/// [`SessionError::Tampered`] and [`SessionError::Expired`], and
+/// respond with a server error for everything else.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{MemoryStore, SessionError, SessionStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// let store = MemoryStore::new();
+/// match store.load_session("not base64!".into()).await {
+///     Err(SessionError::InvalidCookie(_)) => {}
+///     other => panic!("unexpected {:?}", other),
+/// }
+/// # Ok(()) }) }
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SessionError {
+    /// the cookie value is not in a format that the session store
+    /// recognizes
+    InvalidCookie(BoxError),
+
+    /// the cookie value or the stored session failed signature
+    /// verification or decryption, because it was modified or
+    /// protected with an unknown key
+    Tampered,
+
+    /// the session has expired
+    Expired,
+
+    /// a session or a session value could not be serialized or
+    /// deserialized
+    Serialization(BoxError),
+
+    /// the storage backend of a session store failed
+    Backend(BoxError),
+
+    /// the serialized session is larger than the session store allows
+    TooLarge {
+        /// the size in bytes that the session would have needed
+        size: usize,
+        /// the maximum size in bytes that was allowed
+        max_size: usize,
+    },
+
+    /// the session store does not support the named operation
+    Unsupported(&'static str),
+
+    /// the session was stored or destroyed by another request since
+    /// it was loaded, so it was not stored
+    Conflict,
+
+    /// the principal of the session already has as many sessions as
+    /// the session store allows
+    TooManySessions {
+        /// the maximum number of sessions per principal
+        max_sessions: usize,
+    },
+}
+
+impl SessionError {
+    /// constructs a [`SessionError::InvalidCookie`] from any error or
+    /// message
+    pub fn invalid_cookie(error: impl Into<BoxError>) -> Self {
+        Self::InvalidCookie(error.into())
+    }
+
+    /// constructs a [`SessionError::Serialization`] from any error or
+    /// message
+    pub fn serialization(error: impl Into<BoxError>) -> Self {
+        Self::Serialization(error.into())
+    }
+
+    /// constructs a [`SessionError::Backend`] from any error or
+    /// message
+    pub fn backend(error: impl Into<BoxError>) -> Self {
+        Self::Backend(error.into())
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCookie(error) => write!(f, "invalid session cookie: {}", error),
+            Self::Tampered => f.write_str("session cookie failed verification"),
+            Self::Expired => f.write_str("session has expired"),
+            Self::Serialization(error) => write!(f, "session serialization failed: {}", error),
+            Self::Backend(error) => write!(f, "session store backend failed: {}", error),
+            Self::TooLarge { size, max_size } => write!(
+                f,
+                "session of {} bytes exceeds the maximum size of {} bytes",
+                size, max_size
+            ),
+            Self::Unsupported(operation) => {
+                write!(f, "session store does not support {}", operation)
+            }
+            Self::Conflict => f.write_str("session was modified concurrently"),
+            Self::TooManySessions { max_sessions } => write!(
+                f,
+                "principal already has the maximum of {} sessions",
+                max_sessions
+            ),
+        }
+    }
+}
+
+impl StdError for SessionError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::InvalidCookie(error) | Self::Serialization(error) | Self::Backend(error) => {
+                Some(&**error)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<base64::DecodeError> for SessionError {
+    fn from(error: base64::DecodeError) -> Self {
+        Self::InvalidCookie(error.into())
+    }
+}
+
+impl From<serde_json::Error> for SessionError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Serialization(error.into())
+    }
+}
+
+impl From<bincode::Error> for SessionError {
+    fn from(error: bincode::Error) -> Self {
+        Self::Serialization(error)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for SessionError {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::Backend(error.into())
+    }
+}
+
+/// implements conversions from errors that are always failures of the
+/// storage backend
+#[cfg(feature = "redb")]
+macro_rules! backend_errors {
+    ($($error:ty),*) => {$(
+        impl From<$error> for SessionError {
+            fn from(error: $error) -> Self {
+                Self::Backend(error.into())
+            }
+        }
+    )*};
+}
+
+#[cfg(feature = "redb")]
+backend_errors!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
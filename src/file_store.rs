@@ -0,0 +1,650 @@
+use crate::{
+    async_trait, atomic_file, envelope::Format, log, Bincode, Result, Session, SessionCodec,
+    SessionError, SessionInfo, SessionPage, SessionStore,
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// the number of hex digits of a file name that select the
+/// subdirectory it is stored in
+const SHARD_PREFIX_LEN: usize = 2;
+
+/// how long a temporary file may exist before [`FileStore::cleanup`]
+/// considers it left behind by a write that was interrupted, for
+/// example by a crash. this is far longer than any write takes, so
+/// that files that are still being written are never deleted
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(15 * 60);
+
+/// # file system session store
+///
+/// Stores every session in its own file under a directory, so that
+/// sessions survive restarts without running a database. This is
+/// intended for deployments on a single machine: the directory must
+/// not be shared between hosts, for example over a network file
+/// system.
+///
+/// Session ids are base64 and may contain `/` and `+`, and base64 is
+/// case sensitive while some file systems are not, so ids are not used
+/// as file names directly. Instead, every session is stored in a file
+/// that is named after the hex encoded blake3 hash of its id. The
+/// files are spread over up to 256 subdirectories by the first two
+/// digits of their name, so that no single directory grows too large.
+///
+/// Sessions are written to a temporary file that is then renamed
+/// over the previous version, so that a session file is never
+/// partially written. The files use the same versioned format as the
+/// [`CookieStore`](crate::CookieStore), and sessions are serialized
+/// with [`Bincode`] unless another codec is chosen with
+/// [`FileStore::with_codec`].
+///
+/// Expired session files are not deleted when they are loaded, so
+/// [`FileStore::cleanup`] should be run on an intermittent basis.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{FileStore, Session, SessionStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// # let dir = tempfile::tempdir().unwrap();
+/// let store = FileStore::new(dir.path().join("sessions"));
+/// let mut session = Session::new();
+/// session.insert("user_id", 1)?;
+/// let cookie_value = store.store_session(session).await?.unwrap();
+///
+/// let restarted = FileStore::new(dir.path().join("sessions"));
+/// let session = restarted.load_session(cookie_value).await?.unwrap();
+/// assert_eq!(session.get::<usize>("user_id"), Some(1));
+/// # Ok(()) }) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct FileStore<C = Bincode> {
+    dir: PathBuf,
+    format: Format<C>,
+}
+
+impl FileStore<Bincode> {
+    /// constructs a new FileStore that stores sessions under the
+    /// provided directory. the directory is created when the first
+    /// session is stored, if it does not exist yet.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            format: Format::new(Bincode),
+        }
+    }
+}
+
+impl<C: SessionCodec> FileStore<C> {
+    /// serializes sessions with the provided codec instead of
+    /// [`Bincode`]. session files written with any of the built-in
+    /// codecs are recognized on load regardless of this setting.
+    pub fn with_codec<D: SessionCodec>(self, codec: D) -> FileStore<D> {
+        FileStore {
+            dir: self.dir,
+            format: self.format.with_codec(codec),
+        }
+    }
+
+    /// the directory that sessions are stored under
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Deletes the files of all expired sessions, along with temporary
+    /// files that were left behind by writes that never finished, for
+    /// example because the process crashed. This should be run on an
+    /// intermittent basis if this store is run for long enough that
+    /// disk usage is a concern.
+    ///
+    /// Temporary files are only deleted once they are older than
+    /// fifteen minutes, so that writes in progress are not disturbed.
+    /// Other files that can not be read as a session are left in
+    /// place.
+    pub async fn cleanup(&self) -> Result {
+        log::trace!("cleaning up file store `{}`", self.dir.display());
+        let dir = self.dir.clone();
+        let format = self.format.clone();
+        let (removed, removed_temp) = blocking::unblock(move || {
+            let removed = remove_matching(&dir, &format, Session::is_expired)?;
+            Ok((removed, remove_stale_temp_files(&dir)?))
+        })
+        .await
+        .map_err(|error: io::Error| SessionError::backend(error))?;
+        log::trace!(
+            "removed {} expired sessions and {} stale temporary files",
+            removed,
+            removed_temp
+        );
+        Ok(())
+    }
+
+    /// the path of the file that the session with the provided id is
+    /// stored in
+    fn path(&self, id: &str) -> PathBuf {
+        let name = blake3::hash(id.as_bytes()).to_hex();
+        self.dir
+            .join(&name[..SHARD_PREFIX_LEN])
+            .join(&name[SHARD_PREFIX_LEN..])
+    }
+}
+
+/// whether a directory entry is one of the subdirectories that
+/// session files are spread over
+fn is_shard(name: &str) -> bool {
+    name.len() == SHARD_PREFIX_LEN && name.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// the subdirectories of `dir` that session files are spread over
+fn shards(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(error) => return Err(error),
+    };
+
+    let mut shards = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(is_shard) && entry.file_type()?.is_dir() {
+            shards.push(entry.path());
+        }
+    }
+    Ok(shards)
+}
+
+/// removes a file, succeeding if it has already been removed
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// whether a path is a temporary file written by [`atomic_file::write`]
+fn is_temp_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == atomic_file::TEMP_EXTENSION)
+}
+
+/// deletes the temporary files that are older than
+/// [`STALE_TEMP_FILE_AGE`], returning how many were deleted
+fn remove_stale_temp_files(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for shard in shards(dir)? {
+        for entry in fs::read_dir(shard)? {
+            let entry = entry?;
+            let path = entry.path();
+            if !is_temp_file(&path) {
+                continue;
+            }
+
+            let modified = match entry.metadata().and_then(|metadata| metadata.modified()) {
+                Ok(modified) => modified,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error),
+            };
+            if modified
+                .elapsed()
+                .is_ok_and(|age| age > STALE_TEMP_FILE_AGE)
+            {
+                remove_file(&path)?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// lists the sessions that have not expired and whose file names come
+/// after the cursor, in the order of their file names. expired and
+/// unreadable files count towards the limit, so that no more than
+/// `limit + 1` files are read
+fn list<C: SessionCodec>(
+    dir: &Path,
+    format: &Format<C>,
+    cursor: Option<&str>,
+    limit: usize,
+) -> io::Result<SessionPage> {
+    let mut files = vec![];
+    for shard in shards(dir)? {
+        let prefix = shard
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_owned();
+        for entry in fs::read_dir(&shard)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if path.extension().is_none() => format!("{}{}", prefix, name),
+                _ => continue,
+            };
+            if cursor.is_none_or(|cursor| name.as_str() > cursor) {
+                files.push((name, path));
+            }
+        }
+    }
+    files.sort_unstable();
+
+    let mut entries = vec![];
+    for (name, path) in files {
+        if entries.len() > limit {
+            break;
+        }
+
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error),
+        };
+
+        let info = match format.decode_record(&contents) {
+            Ok(Some(session)) if !session.is_expired() => Some(SessionInfo::new(&session)),
+            Ok(_) => None,
+            Err(error) => {
+                log::warn!("skipping session file `{}`: {}", path.display(), error);
+                None
+            }
+        };
+        entries.push((name, info));
+    }
+    Ok(SessionPage::collect(entries, limit))
+}
+
+/// calls a function with the path and contents of every session file,
+/// skipping temporary files and files that can not be read as a
+/// session
+fn for_each_session<C, F>(dir: &Path, format: &Format<C>, mut f: F) -> io::Result<()>
+where
+    C: SessionCodec,
+    F: FnMut(&Path, Session) -> io::Result<()>,
+{
+    for shard in shards(dir)? {
+        for entry in fs::read_dir(shard)? {
+            let path = entry?.path();
+            if is_temp_file(&path) {
+                continue;
+            }
+
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error),
+            };
+
+            match format.decode_record(&contents) {
+                Ok(Some(session)) => f(&path, session)?,
+                Ok(None) => {}
+                Err(error) => log::warn!("skipping session file `{}`: {}", path.display(), error),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// deletes the files of the sessions that match a predicate,
+/// returning how many were deleted
+fn remove_matching<C, F>(dir: &Path, format: &Format<C>, predicate: F) -> io::Result<usize>
+where
+    C: SessionCodec,
+    F: Fn(&Session) -> bool,
+{
+    let mut removed = 0;
+    for_each_session(dir, format, |path, session| {
+        if predicate(&session) {
+            remove_file(path)?;
+            removed += 1;
+        }
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+/// the sessions of a principal that have not expired, ordered by id
+fn principal_sessions<C: SessionCodec>(
+    dir: &Path,
+    format: &Format<C>,
+    principal: &str,
+) -> io::Result<Vec<SessionInfo>> {
+    let mut sessions = vec![];
+    for_each_session(dir, format, |_, session| {
+        if session.principal() == Some(principal) && !session.is_expired() {
+            sessions.push(SessionInfo::new(&session));
+        }
+        Ok(())
+    })?;
+    sessions.sort_unstable_by(|a, b| a.id().cmp(b.id()));
+    Ok(sessions)
+}
+
+#[async_trait]
+impl<C: SessionCodec> SessionStore for FileStore<C> {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        self.load_session_by_id(&id).await
+    }
+
+    async fn load_session_by_id(&self, id: &str) -> Result<Option<Session>> {
+        log::trace!("loading session by id `{}`", id);
+        let path = self.path(id);
+        let contents = match blocking::unblock(move || fs::read(path)).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(SessionError::backend(error)),
+        };
+
+        Ok(self
+            .format
+            .decode_record(&contents)?
+            .filter(|session| session.id() == id)
+            .and_then(Session::validate))
+    }
+
+    /// The file of the session is read before it is written, to give
+    /// the session a version after that of the stored one. Concurrent
+    /// writes of the same session may therefore store the same
+    /// version.
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        log::trace!("storing session by id `{}`", session.id());
+        let path = self.path(session.id());
+        let format = self.format.clone();
+        let session = blocking::unblock(move || {
+            let stored = match fs::read(&path) {
+                Ok(contents) => Some(contents),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+                Err(error) => return Err(SessionError::backend(error)),
+            };
+            let mut session = session;
+            session.advance_version(format.record_version(stored.as_deref()));
+            let contents = format.encode_record(&session)?;
+
+            if let Some(shard) = path.parent() {
+                fs::create_dir_all(shard).map_err(SessionError::backend)?;
+            }
+            atomic_file::write(&path, &contents).map_err(SessionError::backend)?;
+            Ok(session)
+        })
+        .await?;
+
+        session.reset_data_changed();
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        log::trace!("destroying session by id `{}`", session.id());
+        let path = self.path(session.id());
+        blocking::unblock(move || remove_file(&path))
+            .await
+            .map_err(SessionError::backend)
+    }
+
+    async fn clear_store(&self) -> Result {
+        log::trace!("clearing file store `{}`", self.dir.display());
+        let dir = self.dir.clone();
+        blocking::unblock(move || {
+            for shard in shards(&dir)? {
+                fs::remove_dir_all(shard)?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|error: io::Error| SessionError::backend(error))
+    }
+
+    /// This reads every session file in order to find the ids of the
+    /// sessions to delete.
+    async fn clear_prefix(&self, prefix: &str) -> Result {
+        log::trace!(
+            "clearing sessions with prefix `{}` from file store `{}`",
+            prefix,
+            self.dir.display()
+        );
+        let dir = self.dir.clone();
+        let format = self.format.clone();
+        let prefix = prefix.to_owned();
+        blocking::unblock(move || {
+            remove_matching(&dir, &format, |session| session.id().starts_with(&prefix))
+        })
+        .await
+        .map_err(SessionError::backend)?;
+        Ok(())
+    }
+
+    /// Sessions are listed in the order of their file names, which
+    /// are also the cursors. Every page lists the names of all session
+    /// files, but reads at most `limit + 1` of the files. Files of
+    /// expired sessions count towards the limit, so a page may hold
+    /// fewer sessions than that even if it is not the last page.
+    async fn list_sessions(&self, cursor: Option<&str>, limit: usize) -> Result<SessionPage> {
+        let dir = self.dir.clone();
+        let format = self.format.clone();
+        let cursor = cursor.map(str::to_owned);
+        blocking::unblock(move || list(&dir, &format, cursor.as_deref(), limit))
+            .await
+            .map_err(SessionError::backend)
+    }
+
+    /// This reads every session file.
+    async fn list_principal_sessions(&self, principal: &str) -> Result<Vec<SessionInfo>> {
+        let dir = self.dir.clone();
+        let format = self.format.clone();
+        let principal = principal.to_owned();
+        blocking::unblock(move || principal_sessions(&dir, &format, &principal))
+            .await
+            .map_err(SessionError::backend)
+    }
+
+    /// This reads every session file.
+    async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+        log::trace!("destroying sessions of principal `{}`", principal);
+        let dir = self.dir.clone();
+        let format = self.format.clone();
+        let principal = principal.to_owned();
+        blocking::unblock(move || {
+            remove_matching(&dir, &format, |session| {
+                session.principal() == Some(principal.as_str())
+            })
+        })
+        .await
+        .map_err(SessionError::backend)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn store() -> (TempDir, FileStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path().join("sessions"));
+        (dir, store)
+    }
+
+    fn files(store: &FileStore) -> Vec<PathBuf> {
+        shards(store.dir())
+            .unwrap()
+            .into_iter()
+            .flat_map(|shard| fs::read_dir(shard).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .collect()
+    }
+
+    #[async_std::test]
+    async fn storing_and_loading_a_session() -> Result {
+        let (_dir, store) = store();
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let id = session.id().to_owned();
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(id, session.id());
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        assert!(!session.data_changed());
+
+        let path = files(&store).pop().unwrap();
+        assert_eq!(path, store.path(&id));
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.bytes().all(|byte| byte.is_ascii_hexdigit()));
+
+        let mut session = session;
+        session.insert("key", "other value")?;
+        assert_eq!(None, store.store_session(session).await?);
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!("other value", session.get::<String>("key").unwrap());
+        assert_eq!(1, files(&store).len());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn loading_missing_and_expired_sessions() -> Result {
+        let (_dir, store) = store();
+        assert_eq!(
+            None,
+            store
+                .load_session(Session::new().into_cookie_value().unwrap())
+                .await?
+        );
+
+        let mut session = Session::new();
+        session.expire_in(Duration::from_secs(60));
+        let cookie_value = store.store_session(session).await?.unwrap();
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        session.set_expiry(time::OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(session).await?;
+        assert_eq!(None, store.load_session(cookie_value).await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn versioning_stale_writes() -> Result {
+        let (_dir, store) = store();
+        let session = Session::new();
+        let stale = session.clone();
+        let cookie_value = store.store_session(session).await?.unwrap();
+        store.store_session(stale.clone()).await?;
+        store.store_session(stale).await?;
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(3, session.version());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn destroying_a_session() -> Result {
+        let (_dir, store) = store();
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        store.destroy_session(session.clone()).await?;
+        assert_eq!(None, store.load_session(cookie_value).await?);
+        store.destroy_session(session).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn cleaning_up_expired_sessions() -> Result {
+        let (_dir, store) = store();
+        store.cleanup().await?;
+
+        let live = store.store_session(Session::new()).await?.unwrap();
+        let mut session = Session::new();
+        session.set_expiry(time::OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(session).await?;
+        let unrelated = files(&store)[0].with_extension("other");
+        fs::write(&unrelated, b"not a session").unwrap();
+
+        store.cleanup().await?;
+        assert_eq!(2, files(&store).len());
+        assert!(unrelated.exists());
+        assert!(store.load_session(live).await?.is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn cleaning_up_stale_temporary_files() -> Result {
+        let (_dir, store) = store();
+        store.store_session(Session::new()).await?;
+        let path = files(&store).pop().unwrap();
+        let stale = path.with_extension("1.tmp");
+        let fresh = path.with_extension("2.tmp");
+        fs::write(&fresh, b"being written").unwrap();
+        fs::File::create(&stale)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - 2 * STALE_TEMP_FILE_AGE)
+            .unwrap();
+
+        store.cleanup().await?;
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        assert!(path.exists());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn listing_sessions_in_pages() -> Result {
+        let (_dir, store) = store();
+        let mut ids = vec![];
+        for _ in 0..5i8 {
+            let session = Session::new();
+            ids.push(session.id().to_owned());
+            store.store_session(session).await?;
+        }
+        let mut session = Session::new();
+        session.set_expiry(time::OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(session).await?;
+        fs::write(files(&store)[0].with_extension("tmp"), b"not a session").unwrap();
+
+        let mut listed = crate::session_info::list_ids(&store, 2).await?;
+        ids.sort();
+        listed.sort();
+        assert_eq!(ids, listed);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn finding_sessions_by_principal() -> Result {
+        let (_dir, store) = store();
+        let mut ids = vec![];
+        for _ in 0..3i8 {
+            let mut session = Session::new();
+            session.set_principal("user");
+            ids.push(session.id().to_owned());
+            store.store_session(session).await?;
+        }
+        store.store_session(Session::new()).await?;
+        ids.sort();
+
+        let listed = store.list_principal_sessions("user").await?;
+        let listed: Vec<_> = listed.iter().map(|info| info.id().to_owned()).collect();
+        assert_eq!(ids, listed);
+
+        store.destroy_principal_sessions("user").await?;
+        assert!(store.list_principal_sessions("user").await?.is_empty());
+        assert_eq!(1, files(&store).len());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn clearing_the_whole_store() -> Result {
+        let (_dir, store) = store();
+        for _ in 0..3i8 {
+            store.store_session(Session::new()).await?;
+        }
+        let unrelated = store.dir().join("unrelated");
+        fs::write(&unrelated, b"not a session").unwrap();
+
+        let mut session = Session::new();
+        session.set_id(format!("app.{}", session.id()));
+        store.store_session(session).await?;
+        store.clear_prefix("app.").await?;
+        assert_eq!(3, files(&store).len());
+
+        store.clear_store().await?;
+        assert!(files(&store).is_empty());
+        assert!(unrelated.exists());
+        Ok(())
+    }
+}
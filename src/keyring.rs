@@ -0,0 +1,152 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+/// the minimum accepted length in bytes of a secret key
+const MIN_KEY_LEN: usize = 32;
+
+/// the length in bytes of the identifier that is stored alongside
+/// data protected by a [`Keyring`]
+pub(crate) const KEY_ID_LEN: usize = 4;
+
+/// A short, non-secret identifier for a key, derived from the key
+/// itself.
+pub(crate) type KeyId = [u8; KEY_ID_LEN];
+
+/// Key material derived from one secret, so that signing, encryption
+/// and encryption at rest never share a key.
+pub(crate) struct Key {
+    id: KeyId,
+    signing: [u8; 32],
+    encryption: [u8; 32],
+    storage: [u8; 32],
+}
+
+impl Key {
+    fn derive(secret: &[u8]) -> Self {
+        assert!(
+            secret.len() >= MIN_KEY_LEN,
+            "session keys must be at least {} bytes long",
+            MIN_KEY_LEN
+        );
+
+        let id = blake3::derive_key("async-session key id", secret);
+        Self {
+            id: [id[0], id[1], id[2], id[3]],
+            signing: blake3::derive_key("async-session signing key", secret),
+            encryption: blake3::derive_key("async-session encryption key", secret),
+            storage: blake3::derive_key("async-session storage key", secret),
+        }
+    }
+
+    pub(crate) fn id(&self) -> KeyId {
+        self.id
+    }
+
+    pub(crate) fn signing(&self) -> &[u8; 32] {
+        &self.signing
+    }
+
+    pub(crate) fn encryption(&self) -> &[u8; 32] {
+        &self.encryption
+    }
+
+    pub(crate) fn storage(&self) -> &[u8; 32] {
+        &self.storage
+    }
+}
+
+/// # A set of secret keys used to protect session data
+///
+/// A keyring has exactly one primary key, which is used to sign or
+/// encrypt everything that is stored, and any number of retired keys,
+/// which are only used to verify or decrypt data that was stored
+/// before the primary key was rotated. Every protected value carries
+/// the identifier of the key that protected it, so the matching key
+/// is looked up directly rather than by trial and error.
+///
+/// Sessions that were loaded with a retired key are marked with
+/// [`Session::mark_for_reissue`](crate::Session::mark_for_reissue),
+/// so that they are stored again under the primary key at the end of
+/// the request.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{CookieStore, Keyring, Session, SessionStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// let old_store = CookieStore::new(&[1; 32]);
+/// let cookie_value = old_store.store_session(Session::new()).await?.unwrap();
+///
+/// let store = CookieStore::new(Keyring::new(&[2; 32]).with_retired(&[1; 32]));
+/// let session = store.load_session(cookie_value).await?.unwrap();
+/// assert!(session.should_reissue());
+/// # Ok(()) }) }
+/// ```
+#[derive(Clone)]
+pub struct Keyring {
+    primary: Arc<Key>,
+    keys: HashMap<KeyId, Arc<Key>>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("primary", &"..")
+            .field("retired", &(self.keys.len() - 1))
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// Creates a keyring with the provided primary secret key
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is shorter than 32 bytes.
+    pub fn new(primary: &[u8]) -> Self {
+        let primary = Arc::new(Key::derive(primary));
+        let mut keys = HashMap::new();
+        keys.insert(primary.id(), primary.clone());
+        Self { primary, keys }
+    }
+
+    /// Adds a retired secret key to this keyring. Data protected by a
+    /// retired key is still accepted, but nothing new is protected
+    /// with it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is shorter than 32 bytes, or if it is already
+    /// part of this keyring.
+    pub fn with_retired(mut self, retired: &[u8]) -> Self {
+        let retired = Key::derive(retired);
+        assert!(
+            !self.keys.contains_key(&retired.id()),
+            "retired keys must be distinct from all other keys in the keyring"
+        );
+        self.keys.insert(retired.id(), Arc::new(retired));
+        self
+    }
+
+    pub(crate) fn primary(&self) -> &Key {
+        &self.primary
+    }
+
+    /// Looks up a key by its identifier, returning the key along with
+    /// whether it has been retired.
+    pub(crate) fn get(&self, id: KeyId) -> Option<(&Key, bool)> {
+        let key = self.keys.get(&id)?;
+        Some((key, id != self.primary.id()))
+    }
+}
+
+impl From<&[u8]> for Keyring {
+    fn from(primary: &[u8]) -> Self {
+        Self::new(primary)
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for Keyring {
+    fn from(primary: &[u8; N]) -> Self {
+        Self::new(primary)
+    }
+}
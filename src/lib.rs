@@ -46,19 +46,57 @@
     unused_qualifications
 )]
 
-pub use anyhow::Error;
-/// An anyhow::Result with default return type of ()
-pub type Result<T = ()> = std::result::Result<T, Error>;
+/// An alias of [`SessionError`], the error type of this crate
+pub type Error = SessionError;
+/// A Result with a [`SessionError`] and a default return type of ()
+pub type Result<T = ()> = std::result::Result<T, SessionError>;
 
+mod atomic_file;
+mod background;
+mod cached_store;
+mod codec;
+mod compression;
 mod cookie_store;
+mod encrypted_store;
+mod envelope;
+mod error;
+mod file_store;
+mod keyring;
+mod limited_store;
 mod memory_store;
+mod migrating_store;
+mod namespaced_store;
+#[cfg(feature = "redb")]
+mod redb_store;
 mod session;
+mod session_info;
 mod session_store;
+#[cfg(feature = "sqlite")]
+mod sqlite_store;
 
+pub use cached_store::{CachedStore, FlushHandle, WritePolicy};
+#[cfg(feature = "cbor")]
+pub use codec::Cbor;
+#[cfg(feature = "messagepack")]
+pub use codec::MessagePack;
+pub use codec::{Bincode, Json, SessionCodec};
+pub use compression::Compression;
 pub use cookie_store::CookieStore;
-pub use memory_store::MemoryStore;
+pub use encrypted_store::EncryptedStore;
+pub use error::SessionError;
+pub use file_store::FileStore;
+pub use keyring::Keyring;
+pub use limited_store::{LimitPolicy, LimitedStore};
+pub use memory_store::{AutosaveHandle, CleanupHandle, MemoryStore};
+pub use migrating_store::MigratingStore;
+pub use namespaced_store::NamespacedStore;
+#[cfg(feature = "redb")]
+pub use redb_store::RedbStore;
 pub use session::Session;
-pub use session_store::SessionStore;
+pub use session_info::{SessionInfo, SessionPage};
+pub use session_store::{SessionStore, UpdateMode};
+#[cfg(feature = "sqlite")]
+pub use sqlite_store::SqliteStore;
 
 pub use async_trait::async_trait;
 pub use base64;
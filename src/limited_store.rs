@@ -0,0 +1,436 @@
+use crate::{
+    async_trait, log, Result, Session, SessionError, SessionInfo, SessionPage, SessionStore,
+};
+
+/// # What a [`LimitedStore`] does when a principal has too many sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum LimitPolicy {
+    /// the oldest sessions of the principal are destroyed to make room
+    /// for the new session. this is the default.
+    #[default]
+    EvictOldest,
+
+    /// the new session is rejected with
+    /// [`SessionError::TooManySessions`], and the existing sessions of
+    /// the principal are kept
+    RejectNew,
+}
+
+/// # A session store that limits the number of sessions per principal
+///
+/// Wraps any other session store that supports
+/// [`SessionStore::list_principal_sessions`], and caps how many
+/// unexpired sessions every [principal](Session::principal) can have
+/// at the same time. Storing a session that the principal does not
+/// have yet, while the principal already has the maximum number of
+/// sessions, either destroys the oldest sessions of the principal or
+/// fails, according to the [`LimitPolicy`]. Storing a session that
+/// the principal already has is never limited, and sessions without a
+/// principal are stored as they are. The sessions of a principal are
+/// only listed when a session may be new to it, which is when the
+/// session has never been stored under its id, because it was just
+/// created or [regenerated](Session::regenerate), or when its
+/// principal changed since it was loaded. Updating the data of a
+/// session that the principal already has therefore costs nothing
+/// extra.
+///
+/// The age of a session is its [creation time](Session::created), and
+/// sessions that were stored before creation times were recorded are
+/// considered the oldest. Use [`LimitedStore::store_session_evicting`]
+/// to find out which sessions were evicted, for example to notify the
+/// principal.
+///
+/// The limit is enforced on a best-effort basis: the oldest sessions
+/// are evicted after the new session was stored, so that a failed
+/// write evicts nothing, and [`LimitPolicy::RejectNew`] lists the
+/// sessions of the principal before the new session is stored, so
+/// sessions that are stored concurrently for the same principal may
+/// briefly exceed it.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{LimitedStore, MemoryStore, Session, SessionStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// let store = LimitedStore::new(MemoryStore::new(), 2);
+/// let mut cookie_values = vec![];
+/// for _ in 0..3 {
+///     let mut session = Session::new();
+///     session.set_principal("user");
+///     cookie_values.push(store.store_session(session).await?.unwrap());
+/// }
+///
+/// assert_eq!(2, store.list_principal_sessions("user").await?.len());
+/// assert!(store.load_session(cookie_values[0].clone()).await?.is_none());
+/// # Ok(()) }) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct LimitedStore<S> {
+    inner: S,
+    max_sessions: usize,
+    policy: LimitPolicy,
+}
+
+impl<S: SessionStore + Send + Sync> LimitedStore<S> {
+    /// constructs a new LimitedStore that allows every principal at
+    /// most `max_sessions` sessions in the inner store, with
+    /// [`LimitPolicy::EvictOldest`]
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_sessions` is zero.
+    pub fn new(inner: S, max_sessions: usize) -> Self {
+        assert!(
+            max_sessions > 0,
+            "principals must be allowed at least one session"
+        );
+        Self {
+            inner,
+            max_sessions,
+            policy: LimitPolicy::default(),
+        }
+    }
+
+    /// sets what happens when a principal has too many sessions.
+    /// defaults to [`LimitPolicy::EvictOldest`].
+    pub fn with_policy(mut self, policy: LimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// the maximum number of sessions per principal
+    pub fn max_sessions(&self) -> usize {
+        self.max_sessions
+    }
+
+    /// the store that the sessions are stored in
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// stores a session like [`SessionStore::store_session`], and also
+    /// returns the sessions of its principal that were evicted to make
+    /// room for it, oldest first
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::{LimitedStore, MemoryStore, Session, SessionStore};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = LimitedStore::new(MemoryStore::new(), 1);
+    /// let mut first = Session::new();
+    /// first.set_principal("user");
+    /// let id = first.id().to_owned();
+    /// store.store_session(first).await?;
+    ///
+    /// let mut second = Session::new();
+    /// second.set_principal("user");
+    /// let (_, evicted) = store.store_session_evicting(second).await?;
+    /// assert_eq!(id, evicted[0].id());
+    /// # Ok(()) }) }
+    /// ```
+    pub async fn store_session_evicting(
+        &self,
+        session: Session,
+    ) -> Result<(Option<String>, Vec<SessionInfo>)> {
+        if !is_new_to_principal(&session) {
+            return Ok((self.inner.store_session(session).await?, vec![]));
+        }
+
+        self.check_room_for(&session).await?;
+        let (id, principal) = (
+            session.id().to_owned(),
+            session.principal().map(str::to_owned),
+        );
+        let cookie_value = self.inner.store_session(session).await?;
+        let evicted = self.evict_for(principal.as_deref(), &id).await?;
+        Ok((cookie_value, evicted))
+    }
+
+    /// stores a session like [`SessionStore::compare_and_store`], and
+    /// also returns the sessions of its principal that were evicted to
+    /// make room for it, oldest first. no sessions are evicted if the
+    /// session fails to be stored.
+    pub async fn compare_and_store_evicting(
+        &self,
+        session: Session,
+    ) -> Result<(Option<String>, Vec<SessionInfo>)> {
+        if !is_new_to_principal(&session) {
+            return Ok((self.inner.compare_and_store(session).await?, vec![]));
+        }
+
+        self.check_room_for(&session).await?;
+        let (id, principal) = (
+            session.id().to_owned(),
+            session.principal().map(str::to_owned),
+        );
+        let cookie_value = self.inner.compare_and_store(session).await?;
+        let evicted = self.evict_for(principal.as_deref(), &id).await?;
+        Ok((cookie_value, evicted))
+    }
+
+    /// with [`LimitPolicy::RejectNew`], fails if storing the session
+    /// would exceed the limit of its principal
+    async fn check_room_for(&self, session: &Session) -> Result {
+        let principal = match (self.policy, session.principal()) {
+            (LimitPolicy::RejectNew, Some(principal)) => principal,
+            _ => return Ok(()),
+        };
+
+        let sessions = self.inner.list_principal_sessions(principal).await?;
+        let id = session.id();
+        if sessions.len() < self.max_sessions || sessions.iter().any(|info| info.id() == id) {
+            return Ok(());
+        }
+
+        log::debug!(
+            "rejecting session `{}` of principal `{}`, which has {} sessions",
+            id,
+            principal,
+            sessions.len()
+        );
+        Err(SessionError::TooManySessions {
+            max_sessions: self.max_sessions,
+        })
+    }
+
+    /// with [`LimitPolicy::EvictOldest`], destroys the oldest sessions
+    /// of a principal other than the session that was just stored,
+    /// until the principal is back within the limit, returning the
+    /// evicted sessions
+    async fn evict_for(&self, principal: Option<&str>, id: &str) -> Result<Vec<SessionInfo>> {
+        let principal = match (self.policy, principal) {
+            (LimitPolicy::EvictOldest, Some(principal)) => principal,
+            _ => return Ok(vec![]),
+        };
+
+        let mut sessions = self.inner.list_principal_sessions(principal).await?;
+        let excess = match sessions.len().checked_sub(self.max_sessions) {
+            Some(excess) if excess > 0 => excess,
+            _ => return Ok(vec![]),
+        };
+
+        sessions.retain(|info| info.id() != id);
+        sessions.sort_by(|a, b| (a.created(), a.id()).cmp(&(b.created(), b.id())));
+        sessions.truncate(excess);
+        for info in &sessions {
+            log::debug!(
+                "evicting session `{}` of principal `{}`",
+                info.id(),
+                principal
+            );
+            self.inner
+                .destroy_session(Session::with_id(info.id()))
+                .await?;
+        }
+        Ok(sessions)
+    }
+}
+
+/// whether a session may not be one of the sessions of its principal
+/// yet, because it was never stored under its id or its principal
+/// changed since it was loaded
+fn is_new_to_principal(session: &Session) -> bool {
+    session.principal().is_some() && (session.version() == 0 || session.principal_changed())
+}
+
+#[async_trait]
+impl<S: SessionStore + Send + Sync> SessionStore for LimitedStore<S> {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        self.inner.load_session(cookie_value).await
+    }
+
+    async fn load_session_by_id(&self, id: &str) -> Result<Option<Session>> {
+        self.inner.load_session_by_id(id).await
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        let (cookie_value, _) = self.store_session_evicting(session).await?;
+        Ok(cookie_value)
+    }
+
+    async fn compare_and_store(&self, session: Session) -> Result<Option<String>> {
+        let (cookie_value, _) = self.compare_and_store_evicting(session).await?;
+        Ok(cookie_value)
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        self.inner.destroy_session(session).await
+    }
+
+    async fn clear_store(&self) -> Result {
+        self.inner.clear_store().await
+    }
+
+    async fn clear_prefix(&self, prefix: &str) -> Result {
+        self.inner.clear_prefix(prefix).await
+    }
+
+    async fn list_sessions(&self, cursor: Option<&str>, limit: usize) -> Result<SessionPage> {
+        self.inner.list_sessions(cursor, limit).await
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<SessionPage> {
+        self.inner.list_prefix(prefix, cursor, limit).await
+    }
+
+    async fn list_principal_sessions(&self, principal: &str) -> Result<Vec<SessionInfo>> {
+        self.inner.list_principal_sessions(principal).await
+    }
+
+    async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+        self.inner.destroy_principal_sessions(principal).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryStore;
+
+    fn session(principal: &str) -> Session {
+        let mut session = Session::new();
+        session.set_principal(principal);
+        session
+    }
+
+    async fn ids(store: &LimitedStore<MemoryStore>, principal: &str) -> Result<Vec<String>> {
+        let sessions = store.list_principal_sessions(principal).await?;
+        Ok(sessions.iter().map(|info| info.id().to_owned()).collect())
+    }
+
+    #[async_std::test]
+    async fn evicting_the_oldest_sessions() -> Result {
+        let store = LimitedStore::new(MemoryStore::new(), 2);
+        let mut cookie_values = vec![];
+        let created = time::OffsetDateTime::now_utc();
+        for age in [3, 2, 1] {
+            let mut session = session("user");
+            session.set_created(created - std::time::Duration::from_secs(age));
+            cookie_values.push(store.store_session(session).await?.unwrap());
+        }
+
+        assert_eq!(2, ids(&store, "user").await?.len());
+        assert!(store
+            .load_session(cookie_values[0].clone())
+            .await?
+            .is_none());
+        assert!(store
+            .load_session(cookie_values[1].clone())
+            .await?
+            .is_some());
+        assert!(store
+            .load_session(cookie_values[2].clone())
+            .await?
+            .is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn reporting_evicted_sessions() -> Result {
+        let store = LimitedStore::new(MemoryStore::new(), 1);
+        let first = session("user");
+        let id = first.id().to_owned();
+        store.store_session(first).await?;
+        store.store_session(session("other")).await?;
+
+        let second = session("user");
+        let second_id = second.id().to_owned();
+        let (cookie_value, evicted) = store.store_session_evicting(second).await?;
+        assert!(cookie_value.is_some());
+        assert_eq!(1, evicted.len());
+        assert_eq!(id, evicted[0].id());
+        assert_eq!(Some("user"), evicted[0].principal());
+        assert_eq!(vec![second_id], ids(&store, "user").await?);
+        assert_eq!(1, ids(&store, "other").await?.len());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn evicting_only_after_storing() -> Result {
+        let store = LimitedStore::new(MemoryStore::new(), 1);
+        let first = session("user");
+        let id = first.id().to_owned();
+        store.store_session(first).await?;
+
+        let anonymous = Session::new();
+        let mut stale = anonymous.clone();
+        let cookie_value = store.store_session(anonymous).await?.unwrap();
+        stale.set_principal("user");
+        let result = store.compare_and_store_evicting(stale).await;
+        assert!(matches!(result, Err(SessionError::Conflict)));
+        assert_eq!(vec![id.clone()], ids(&store, "user").await?);
+
+        let mut session = store.load_session(cookie_value).await?.unwrap();
+        session.set_principal("user");
+        let (_, evicted) = store.compare_and_store_evicting(session).await?;
+        assert_eq!(1, evicted.len());
+        assert_eq!(id, evicted[0].id());
+        assert_eq!(1, ids(&store, "user").await?.len());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejecting_new_sessions() -> Result {
+        let store = LimitedStore::new(MemoryStore::new(), 2).with_policy(LimitPolicy::RejectNew);
+        for _ in 0..2i8 {
+            store.store_session(session("user")).await?;
+        }
+        let before = ids(&store, "user").await?;
+
+        let result = store.store_session(session("user")).await;
+        assert!(matches!(
+            result,
+            Err(SessionError::TooManySessions { max_sessions: 2 })
+        ));
+        assert_eq!(before, ids(&store, "user").await?);
+        assert_eq!(2, store.inner().count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn storing_existing_sessions() -> Result {
+        let store = LimitedStore::new(MemoryStore::new(), 1).with_policy(LimitPolicy::RejectNew);
+        let cookie_value = store.store_session(session("user")).await?.unwrap();
+
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        session.insert("key", "value")?;
+        store.store_session(session).await?;
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!("value", session.get::<String>("key").unwrap());
+
+        for _ in 0..3i8 {
+            store.store_session(Session::new()).await?;
+        }
+        assert_eq!(4, store.inner().count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn updating_sessions_over_the_limit() -> Result {
+        for policy in [LimitPolicy::EvictOldest, LimitPolicy::RejectNew] {
+            let store = LimitedStore::new(MemoryStore::new(), 1).with_policy(policy);
+            let cookie_value = store.inner().store_session(session("user")).await?.unwrap();
+            store.inner().store_session(session("user")).await?;
+
+            let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+            session.insert("key", "value")?;
+            let (_, evicted) = store.store_session_evicting(session).await?;
+            assert!(evicted.is_empty());
+            assert_eq!(2, ids(&store, "user").await?.len());
+
+            let mut session = store.load_session(cookie_value).await?.unwrap();
+            session.insert("key", "other value")?;
+            let (_, evicted) = store.compare_and_store_evicting(session).await?;
+            assert!(evicted.is_empty());
+            assert_eq!(2, ids(&store, "user").await?.len());
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,19 @@
-use crate::{async_trait, log, Result, Session, SessionStore};
+use crate::{
+    async_trait, atomic_file, background, envelope::Format, log, session_info::IdRange, Bincode,
+    Result, Session, SessionError, SessionInfo, SessionPage, SessionStore, UpdateMode,
+};
 use async_lock::RwLock;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap},
+    fs,
+    future::Future,
+    hash::BuildHasher,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use time::OffsetDateTime;
 
 /// # in-memory session store
 /// Because there is no external
@@ -12,45 +25,298 @@ use std::{collections::HashMap, sync::Arc};
 /// Storing sessions only in memory brings the following problems:
 ///
 /// 1. All sessions must fit in available memory (important for high load services)
-/// 2. Sessions stored in memory are cleared only if a client calls [MemoryStore::destroy_session] or [MemoryStore::clear_store].
-///    If sessions are not cleaned up properly it might result in OOM
-/// 3. All sessions will be lost on shutdown
+/// 2. Sessions stored in memory are cleared only if a client calls [MemoryStore::destroy_session] or [MemoryStore::clear_store],
+///    or when expired sessions are removed with [MemoryStore::cleanup] or by a task built with [MemoryStore::cleanup_task].
+///    If sessions are not cleaned up properly it might result in OOM.
+///    A store created with [MemoryStore::with_capacity] bounds the number of sessions it holds instead.
+/// 3. All sessions will be lost on shutdown, unless they are saved with [MemoryStore::save_snapshot]
+///    and restored with [MemoryStore::load_snapshot]
 /// 4. If the service is clustered particular session will be stored only on a single instance.
 ///    This might be solved by using load balancers with sticky sessions.
 ///    Unfortunately, this solution brings additional complexity especially if the connection is
 ///    using secure transport since the load balancer has to perform SSL termination to understand
 ///    where should it forward packets to
 ///
+/// Sessions are indexed by their [principal](Session::set_principal), so
+/// that listing or destroying the sessions of a principal only visits
+/// those sessions.
+///
+/// Loaded sessions are copies of the stored ones, so changes to them
+/// only take effect once they are stored again.
+/// [`SessionStore::compare_and_store`] compares the version of a
+/// session and stores it under the lock of its shard, so it never
+/// overwrites a concurrent write.
+///
+/// This crate provides persistent alternatives in [`FileStore`](crate::FileStore),
+/// `SqliteStore` with the `sqlite` feature, and `RedbStore` with the `redb` feature.
+///
 /// Example crates providing alternative implementations:
 /// - [async-sqlx-session](https://crates.io/crates/async-sqlx-session) postgres & sqlite
 /// - [async-redis-session](https://crates.io/crates/async-redis-session)
 /// - [async-mongodb-session](https://crates.io/crates/async-mongodb-session)
 ///
-#[derive(Default, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct MemoryStore {
-    inner: Arc<RwLock<HashMap<String, Session>>>,
+    shards: Arc<[Shard]>,
+    hasher: RandomState,
+    update_mode: UpdateMode,
+}
+
+/// An independently locked part of a [`MemoryStore`]. Every session
+/// is stored in the shard that its id hashes to.
+#[derive(Debug)]
+struct Shard {
+    sessions: RwLock<Sessions>,
+    capacity: Option<usize>,
+}
+
+/// The sessions of a [`MemoryStore`], along with the bookkeeping
+/// needed to expire and evict them.
+#[derive(Default, Debug)]
+struct Sessions {
+    entries: HashMap<String, Entry>,
+    /// the ids of all sessions, in order, so that sessions can be
+    /// listed from a cursor without visiting every session
+    ids: BTreeSet<String>,
+    /// the ids of sessions that have an expiry, ordered by that expiry,
+    /// so that expired sessions can be found without visiting every
+    /// session
+    expiries: BTreeSet<(OffsetDateTime, String)>,
+    /// session ids by the tick at which they were last used, which is
+    /// only tracked for bounded stores. the first entry is the least
+    /// recently used session
+    recency: BTreeMap<u64, String>,
+    /// the ids of sessions that have a principal, by that principal
+    principals: HashMap<String, BTreeSet<String>>,
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    session: Session,
+    last_used: u64,
+}
+
+impl Sessions {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&self, id: &str) -> Option<Session> {
+        self.entries.get(id).map(|entry| entry.session.snapshot())
+    }
+
+    /// looks up a session and marks it as the most recently used one
+    fn touch(&mut self, id: &str) -> Option<Session> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(id)?;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(tick, id.to_owned());
+        entry.last_used = tick;
+        Some(entry.session.snapshot())
+    }
+
+    fn insert(&mut self, session: Session, capacity: Option<usize>) {
+        let id = session.id().to_owned();
+        let replaced = self.remove(&id).is_some();
+        let last_used = match capacity {
+            Some(capacity) => {
+                if !replaced && self.entries.len() >= capacity {
+                    self.evict(capacity - 1);
+                }
+                let tick = self.next_tick();
+                self.recency.insert(tick, id.clone());
+                tick
+            }
+            None => 0,
+        };
+
+        if let Some(expiry) = session.expiry() {
+            self.expiries.insert((*expiry, id.clone()));
+        }
+        if let Some(principal) = session.principal() {
+            self.principals
+                .entry(principal.to_owned())
+                .or_default()
+                .insert(id.clone());
+        }
+        self.ids.insert(id.clone());
+        self.entries.insert(id, Entry { session, last_used });
+    }
+
+    /// stores a copy of a session with a version after both its own
+    /// and that of the stored copy. in [`UpdateMode::Merge`], only its
+    /// changed keys are applied to the data of the stored copy, if
+    /// there is an unexpired one
+    fn write(&mut self, session: &Session, update_mode: UpdateMode, capacity: Option<usize>) {
+        let stored = self.entries.get(session.id()).map(|entry| &entry.session);
+        let version = stored.map_or(0, Session::version).max(session.version()) + 1;
+        let mut record = match (update_mode, stored) {
+            (UpdateMode::Merge, Some(stored)) if !stored.is_expired() => {
+                session.merge_snapshot(stored)
+            }
+            _ => session.snapshot(),
+        };
+        record.set_version(version);
+        self.insert(record, capacity);
+    }
+
+    fn remove(&mut self, id: &str) -> Option<Session> {
+        let entry = self.entries.remove(id)?;
+        self.ids.remove(id);
+        self.recency.remove(&entry.last_used);
+        if let Some(expiry) = entry.session.expiry() {
+            self.expiries.remove(&(*expiry, id.to_owned()));
+        }
+        if let Some(principal) = entry.session.principal() {
+            if let Some(ids) = self.principals.get_mut(principal) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.principals.remove(principal);
+                }
+            }
+        }
+        Some(entry.session)
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.ids.clear();
+        self.expiries.clear();
+        self.recency.clear();
+        self.principals.clear();
+    }
+
+    /// the sessions of a principal, ordered by id
+    fn principal_sessions<'a>(&'a self, principal: &str) -> impl Iterator<Item = &'a Session> {
+        self.principals
+            .get(principal)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.entries.get(id))
+            .map(|entry| &entry.session)
+    }
+
+    /// removes every session of a principal
+    fn remove_principal(&mut self, principal: &str) {
+        for id in self.principals.remove(principal).unwrap_or_default() {
+            self.remove(&id);
+        }
+    }
+
+    /// removes every session whose id starts with the provided prefix
+    fn remove_prefix(&mut self, prefix: &str) {
+        let ids: Vec<String> = match IdRange::new(prefix, None) {
+            Some(range) => self.ids.range::<str, _>(range.bounds()).cloned().collect(),
+            None => return,
+        };
+        for id in ids {
+            self.remove(&id);
+        }
+    }
+
+    /// the first `limit` sessions in a range of ids, ordered by id,
+    /// with expired sessions left out but counted towards the limit
+    fn list(&self, range: &IdRange, limit: usize) -> Vec<(String, Option<SessionInfo>)> {
+        self.ids
+            .range::<str, _>(range.bounds())
+            .take(limit)
+            .map(|id| {
+                let session = &self.entries[id].session;
+                let info = (!session.is_expired()).then(|| SessionInfo::new(session));
+                (id.clone(), info)
+            })
+            .collect()
+    }
+
+    /// removes every expired session, returning how many were removed
+    fn remove_expired(&mut self) -> usize {
+        let now = OffsetDateTime::now_utc();
+        let mut removed = 0;
+        while let Some((expiry, id)) = self.expiries.first().cloned() {
+            if expiry > now {
+                break;
+            }
+            log::trace!("removing expired session `{}`", id);
+            self.remove(&id);
+            removed += 1;
+        }
+        removed
+    }
+
+    /// evicts sessions until at most `len` remain. expired sessions
+    /// are always evicted first, followed by the least recently used
+    /// sessions
+    fn evict(&mut self, len: usize) {
+        self.remove_expired();
+
+        while self.entries.len() > len {
+            let id = match self.recency.values().next() {
+                Some(id) => id.clone(),
+                None => break,
+            };
+            log::trace!("evicting least recently used session `{}`", id);
+            self.remove(&id);
+        }
+    }
 }
 
 #[async_trait]
 impl SessionStore for MemoryStore {
     async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
         let id = Session::id_from_cookie_value(&cookie_value)?;
+        self.load_session_by_id(&id).await
+    }
+
+    async fn load_session_by_id(&self, id: &str) -> Result<Option<Session>> {
         log::trace!("loading session by id `{}`", id);
-        Ok(self
-            .inner
-            .read()
-            .await
-            .get(&id)
-            .cloned()
-            .and_then(Session::validate))
+        let shard = self.shard(id);
+        let session = if shard.capacity.is_some() {
+            shard.sessions.write().await.touch(id)
+        } else {
+            shard.sessions.read().await.get(id)
+        };
+        Ok(session.and_then(Session::validate))
     }
 
     async fn store_session(&self, session: Session) -> Result<Option<String>> {
         log::trace!("storing session by id `{}`", session.id());
-        self.inner
+        let shard = self.shard(session.id());
+        shard
+            .sessions
             .write()
             .await
-            .insert(session.id().to_string(), session.clone());
+            .write(&session, self.update_mode, shard.capacity);
+
+        session.reset_data_changed();
+        Ok(session.into_cookie_value())
+    }
+
+    async fn compare_and_store(&self, session: Session) -> Result<Option<String>> {
+        log::trace!(
+            "storing session by id `{}` if it is at version {}",
+            session.id(),
+            session.version()
+        );
+        let shard = self.shard(session.id());
+        let mut sessions = shard.sessions.write().await;
+        let stored_version = sessions
+            .entries
+            .get(session.id())
+            .map_or(0, |entry| entry.session.version());
+        if stored_version != session.version() {
+            log::debug!(
+                "session `{}` is at version {} instead of {}",
+                session.id(),
+                stored_version,
+                session.version()
+            );
+            return Err(SessionError::Conflict);
+        }
+
+        sessions.write(&session, self.update_mode, shard.capacity);
+        drop(sessions);
 
         session.reset_data_changed();
         Ok(session.into_cookie_value())
@@ -58,49 +324,481 @@ impl SessionStore for MemoryStore {
 
     async fn destroy_session(&self, session: Session) -> Result {
         log::trace!("destroying session by id `{}`", session.id());
-        self.inner.write().await.remove(session.id());
+        let shard = self.shard(session.id());
+        shard.sessions.write().await.remove(session.id());
         Ok(())
     }
 
     async fn clear_store(&self) -> Result {
         log::trace!("clearing memory store");
-        self.inner.write().await.clear();
+        for shard in self.shards.iter() {
+            shard.sessions.write().await.clear();
+        }
+        Ok(())
+    }
+
+    async fn clear_prefix(&self, prefix: &str) -> Result {
+        log::trace!(
+            "clearing sessions with prefix `{}` from memory store",
+            prefix
+        );
+        for shard in self.shards.iter() {
+            shard.sessions.write().await.remove_prefix(prefix);
+        }
+        Ok(())
+    }
+
+    /// Sessions are listed in the order of their ids, which are also
+    /// the cursors. Every page visits at most `limit + 1` sessions in
+    /// every shard.
+    async fn list_sessions(&self, cursor: Option<&str>, limit: usize) -> Result<SessionPage> {
+        self.list_prefix("", cursor, limit).await
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<SessionPage> {
+        let range = match IdRange::new(prefix, cursor) {
+            Some(range) => range,
+            None => return Ok(SessionPage::collect(vec![], limit)),
+        };
+
+        // the first entries of the page are among the first entries
+        // of every shard
+        let mut entries = vec![];
+        for shard in self.shards.iter() {
+            let shard = shard.sessions.read().await;
+            entries.extend(shard.list(&range, limit.saturating_add(1)));
+        }
+
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(SessionPage::collect(entries, limit))
+    }
+
+    async fn list_principal_sessions(&self, principal: &str) -> Result<Vec<SessionInfo>> {
+        let mut sessions = vec![];
+        for shard in self.shards.iter() {
+            let shard = shard.sessions.read().await;
+            sessions.extend(
+                shard
+                    .principal_sessions(principal)
+                    .filter(|session| !session.is_expired())
+                    .map(SessionInfo::new),
+            );
+        }
+
+        sessions.sort_unstable_by(|a, b| a.id().cmp(b.id()));
+        Ok(sessions)
+    }
+
+    async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+        log::trace!("destroying sessions of principal `{}`", principal);
+        for shard in self.shards.iter() {
+            shard.sessions.write().await.remove_principal(principal);
+        }
         Ok(())
     }
 }
 
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::build(None, 1)
+    }
+}
+
 impl MemoryStore {
     /// Create a new instance of MemoryStore
     pub fn new() -> Self {
         Self::default()
     }
 
+    /// Create a new instance of MemoryStore that holds at most
+    /// `capacity` sessions. When a new session is stored in a full
+    /// store, expired sessions are evicted first, and then the least
+    /// recently used session. Both loading and storing a session
+    /// count as using it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use async_session::{MemoryStore, Session, SessionStore};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = MemoryStore::with_capacity(2);
+    /// let first = store.store_session(Session::new()).await?.unwrap();
+    /// let second = store.store_session(Session::new()).await?.unwrap();
+    /// assert!(store.load_session(first.clone()).await?.is_some());
+    ///
+    /// store.store_session(Session::new()).await?;
+    /// assert_eq!(store.count().await, 2);
+    /// assert!(store.load_session(first).await?.is_some());
+    /// assert!(store.load_session(second).await?.is_none());
+    /// # Ok(()) }) }
+    /// ```
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0, "MemoryStore capacity must not be zero");
+        Self::build(Some(capacity), 1)
+    }
+
+    /// Create a new instance of MemoryStore that is split into
+    /// `shards` independently locked shards. Every session is stored
+    /// in the shard that its id hashes to, so that sessions in
+    /// different shards can be loaded and stored concurrently. This
+    /// reduces lock contention when many tasks use the store in
+    /// parallel, and a shard count around the number of cpu cores is
+    /// a reasonable starting point.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use async_session::{MemoryStore, Session, SessionStore};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = MemoryStore::with_shards(16);
+    /// let cookie_value = store.store_session(Session::new()).await?.unwrap();
+    /// assert!(store.load_session(cookie_value).await?.is_some());
+    /// assert_eq!(store.count().await, 1);
+    /// # Ok(()) }) }
+    /// ```
+    pub fn with_shards(shards: usize) -> Self {
+        Self::build(None, shards)
+    }
+
+    /// Create a new instance of MemoryStore that holds at most
+    /// `capacity` sessions, split into `shards` independently locked
+    /// shards. See [`MemoryStore::with_capacity`] and
+    /// [`MemoryStore::with_shards`].
+    ///
+    /// The capacity is divided evenly between the shards, and
+    /// sessions are evicted from the shard that a new session is
+    /// stored in. The session that is evicted is therefore the least
+    /// recently used session of that shard, which is not necessarily
+    /// the least recently used session of the whole store.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero, or if `capacity` is less than
+    /// `shards`.
+    pub fn with_capacity_and_shards(capacity: usize, shards: usize) -> Self {
+        assert!(
+            capacity >= shards,
+            "MemoryStore capacity must be at least the number of shards"
+        );
+        Self::build(Some(capacity), shards)
+    }
+
+    fn build(capacity: Option<usize>, shards: usize) -> Self {
+        assert!(shards > 0, "MemoryStore must have at least one shard");
+        let shards = (0..shards)
+            .map(|index| Shard {
+                sessions: RwLock::default(),
+                capacity: capacity
+                    .map(|capacity| capacity / shards + usize::from(index < capacity % shards)),
+            })
+            .collect();
+
+        Self {
+            shards,
+            hasher: RandomState::new(),
+            update_mode: UpdateMode::default(),
+        }
+    }
+
+    /// sets how sessions that are already stored are written.
+    /// defaults to [`UpdateMode::Replace`]. with [`UpdateMode::Merge`],
+    /// the changed keys of a session are applied to the stored data
+    /// under the lock of its shard, so changes to different keys by
+    /// concurrent requests are all kept. sessions that are wrapped by
+    /// another store, such as an
+    /// [`EncryptedStore`](crate::EncryptedStore), are still written
+    /// as a whole.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use async_session::{MemoryStore, Session, SessionStore, UpdateMode};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = MemoryStore::new().with_update_mode(UpdateMode::Merge);
+    /// let cookie_value = store.store_session(Session::new()).await?.unwrap();
+    ///
+    /// let mut first = store.load_session(cookie_value.clone()).await?.unwrap();
+    /// let mut second = store.load_session(cookie_value.clone()).await?.unwrap();
+    /// first.insert("cart", 3)?;
+    /// second.insert("theme", "dark")?;
+    /// store.store_session(first).await?;
+    /// store.store_session(second).await?;
+    ///
+    /// let session = store.load_session(cookie_value).await?.unwrap();
+    /// assert_eq!(Some(3), session.get::<usize>("cart"));
+    /// assert_eq!(Some("dark".to_owned()), session.get::<String>("theme"));
+    /// # Ok(()) }) }
+    /// ```
+    pub fn with_update_mode(mut self, update_mode: UpdateMode) -> Self {
+        self.update_mode = update_mode;
+        self
+    }
+
+    /// the shard that the session with the provided id is stored in
+    fn shard(&self, id: &str) -> &Shard {
+        match self.shards.len() {
+            1 => &self.shards[0],
+            len => &self.shards[(self.hasher.hash_one(id) % len as u64) as usize],
+        }
+    }
+
     /// Performs session cleanup. This should be run on an
     /// intermittent basis if this store is run for long enough that
     /// memory accumulation is a concern
     pub async fn cleanup(&self) -> Result {
         log::trace!("cleaning up memory store...");
-        let ids_to_delete: Vec<_> = self
-            .inner
-            .read()
+        let mut removed = 0;
+        for shard in self.shards.iter() {
+            removed += shard.sessions.write().await.remove_expired();
+        }
+        log::trace!("removed {} expired sessions", removed);
+        Ok(())
+    }
+
+    /// Spawns a background task that calls [`MemoryStore::cleanup`]
+    /// every `interval`, until the returned [`CleanupHandle`] is
+    /// dropped.
+    ///
+    /// The task is spawned on the tokio runtime of the current thread
+    /// if the `tokio` feature is enabled and there is one, and on the
+    /// async-std executor otherwise. To run the task on any other
+    /// executor, see [`MemoryStore::cleanup_task`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if only the `tokio` feature is enabled, and this is not
+    /// called from within a tokio runtime.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use async_session::{MemoryStore, Session, SessionStore};
+    /// # use std::time::Duration;
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = MemoryStore::new();
+    /// let cleanup = store.spawn_cleanup(Duration::from_secs(60));
+    /// // expired sessions are now removed every minute, until
+    /// drop(cleanup);
+    /// # Ok(()) }) }
+    /// ```
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    pub fn spawn_cleanup(&self, interval: Duration) -> CleanupHandle {
+        let store = self.clone();
+        let stopper = background::spawn(interval, move || {
+            let store = store.clone();
+            async move { store.cleanup().await }
+        });
+
+        CleanupHandle {
+            store: self.clone(),
+            _stopper: stopper,
+        }
+    }
+
+    /// Builds a task that calls [`MemoryStore::cleanup`] every
+    /// `interval`, until the returned [`CleanupHandle`] is dropped.
+    /// The task waits between runs with the provided `sleep`
+    /// function, and must be spawned on an executor by the caller.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use async_session::MemoryStore;
+    /// # use std::time::Duration;
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = MemoryStore::new();
+    /// let (cleanup, task) = store.cleanup_task(Duration::from_secs(60), async_std::task::sleep);
+    /// let task = async_std::task::spawn(task);
+    ///
+    /// drop(cleanup);
+    /// task.await;
+    /// # Ok(()) }) }
+    /// ```
+    pub fn cleanup_task<S, F>(
+        &self,
+        interval: Duration,
+        sleep: S,
+    ) -> (CleanupHandle, impl Future<Output = ()> + Send + 'static)
+    where
+        S: Fn(Duration) -> F + Send + 'static,
+        F: Future<Output = ()> + Send,
+    {
+        let store = self.clone();
+        let (stopper, task) = background::task(interval, sleep, move || {
+            let store = store.clone();
+            async move { store.cleanup().await }
+        });
+
+        let handle = CleanupHandle {
+            store: self.clone(),
+            _stopper: stopper,
+        };
+        (handle, task)
+    }
+
+    /// Saves every session that has not expired to a snapshot file at
+    /// `path`, along with its id and expiry, so that the sessions can
+    /// be restored with [`MemoryStore::load_snapshot`] after a
+    /// restart.
+    ///
+    /// The snapshot is written to a temporary file next to `path`
+    /// that is then renamed over it, so that `path` holds either the
+    /// previous or the new snapshot even if saving is interrupted.
+    ///
+    /// Unlike the stores that take a
+    /// [`SessionCodec`](crate::SessionCodec), snapshots always
+    /// serialize sessions with [`Bincode`]: a snapshot is a private
+    /// file of this store that is only ever read back by
+    /// [`MemoryStore::load_snapshot`], so there is nothing for another
+    /// codec to be compatible with.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use async_session::{MemoryStore, Session, SessionStore};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # let path = dir.path().join("sessions");
+    /// let store = MemoryStore::new();
+    /// let cookie_value = store.store_session(Session::new()).await?.unwrap();
+    /// store.save_snapshot(&path).await?;
+    ///
+    /// let restarted = MemoryStore::new();
+    /// restarted.load_snapshot(&path).await?;
+    /// assert!(restarted.load_session(cookie_value).await?.is_some());
+    /// # Ok(()) }) }
+    /// ```
+    pub async fn save_snapshot(&self, path: impl AsRef<Path>) -> Result {
+        let path = path.as_ref().to_owned();
+        let mut sessions = vec![];
+        for shard in self.shards.iter() {
+            let shard = shard.sessions.read().await;
+            sessions.extend(
+                shard
+                    .entries
+                    .values()
+                    .filter(|entry| !entry.session.is_expired())
+                    .map(|entry| entry.session.clone()),
+            );
+        }
+
+        let format = Format::new(Bincode);
+        let records = sessions
+            .iter()
+            .map(|session| format.encode_record(session))
+            .collect::<Result<Vec<_>>>()?;
+
+        log::trace!(
+            "saving {} sessions to snapshot `{}`",
+            records.len(),
+            path.display()
+        );
+        let mut contents = SNAPSHOT_MAGIC.to_vec();
+        contents.extend(bincode::serialize(&records)?);
+        blocking::unblock(move || atomic_file::write(&path, &contents))
             .await
-            .values()
-            .filter_map(|session| {
-                if session.is_expired() {
-                    Some(session.id().to_owned())
-                } else {
-                    None
-                }
-            })
-            .collect();
+            .map_err(SessionError::backend)
+    }
 
-        log::trace!("found {} expired sessions", ids_to_delete.len());
-        for id in ids_to_delete {
-            self.inner.write().await.remove(&id);
+    /// Restores the sessions from a snapshot file that was written by
+    /// [`MemoryStore::save_snapshot`] into this store. Sessions that
+    /// have expired since the snapshot was saved are skipped. If
+    /// there is no file at `path`, for example on the very first
+    /// start, this does nothing.
+    pub async fn load_snapshot(&self, path: impl AsRef<Path>) -> Result {
+        let path = path.as_ref().to_owned();
+        log::trace!("loading snapshot `{}`", path.display());
+        let contents = match blocking::unblock(move || fs::read(path)).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(SessionError::backend(error)),
+        };
+
+        let records: Vec<Vec<u8>> = match contents.strip_prefix(SNAPSHOT_MAGIC) {
+            Some(records) => bincode::deserialize(records)?,
+            None => return Err(SessionError::serialization("not a MemoryStore snapshot")),
+        };
+
+        let format = Format::new(Bincode);
+        for record in records {
+            match format.decode_record(&record)? {
+                Some(session) if !session.is_expired() => {
+                    let shard = self.shard(session.id());
+                    shard.sessions.write().await.insert(session, shard.capacity);
+                }
+                Some(_) => {}
+                None => log::warn!("skipping session of unknown version in snapshot"),
+            }
         }
         Ok(())
     }
 
+    /// Spawns a background task that calls
+    /// [`MemoryStore::save_snapshot`] with `path` every `interval`,
+    /// until the returned [`AutosaveHandle`] is dropped. The task is
+    /// spawned like the one of [`MemoryStore::spawn_cleanup`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if only the `tokio` feature is enabled, and this is not
+    /// called from within a tokio runtime.
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    pub fn spawn_autosave(&self, path: impl AsRef<Path>, interval: Duration) -> AutosaveHandle {
+        let store = self.clone();
+        let path = path.as_ref().to_owned();
+        let task_path = path.clone();
+        let stopper = background::spawn(interval, move || {
+            let store = store.clone();
+            let path = task_path.clone();
+            async move { store.save_snapshot(path).await }
+        });
+
+        AutosaveHandle {
+            store: self.clone(),
+            path,
+            _stopper: stopper,
+        }
+    }
+
+    /// Builds a task that calls [`MemoryStore::save_snapshot`] with
+    /// `path` every `interval`, until the returned [`AutosaveHandle`]
+    /// is dropped. Like [`MemoryStore::cleanup_task`], the task waits
+    /// with the provided `sleep` function and must be spawned by the
+    /// caller.
+    pub fn autosave_task<S, F>(
+        &self,
+        path: impl AsRef<Path>,
+        interval: Duration,
+        sleep: S,
+    ) -> (AutosaveHandle, impl Future<Output = ()> + Send + 'static)
+    where
+        S: Fn(Duration) -> F + Send + 'static,
+        F: Future<Output = ()> + Send,
+    {
+        let store = self.clone();
+        let path = path.as_ref().to_owned();
+        let task_path = path.clone();
+        let (stopper, task) = background::task(interval, sleep, move || {
+            let store = store.clone();
+            let path = task_path.clone();
+            async move { store.save_snapshot(path).await }
+        });
+
+        let handle = AutosaveHandle {
+            store: self.clone(),
+            path,
+            _stopper: stopper,
+        };
+        (handle, task)
+    }
+
     /// returns the number of elements in the memory store
     /// # Example
     /// ```rust
@@ -113,8 +811,55 @@ impl MemoryStore {
     /// # Ok(()) }) }
     /// ```
     pub async fn count(&self) -> usize {
-        let data = self.inner.read().await;
-        data.len()
+        let mut count = 0;
+        for shard in self.shards.iter() {
+            count += shard.sessions.read().await.entries.len();
+        }
+        count
+    }
+}
+
+/// the bytes that every snapshot written by
+/// [`MemoryStore::save_snapshot`] starts with
+const SNAPSHOT_MAGIC: &[u8] = b"async-session snapshot 1\n";
+
+/// # A handle to a cleanup task of a [`MemoryStore`]
+///
+/// The task is stopped when this handle is dropped. See
+/// `MemoryStore::spawn_cleanup` and [`MemoryStore::cleanup_task`].
+#[derive(Debug)]
+#[must_use = "the cleanup task is stopped when its handle is dropped"]
+pub struct CleanupHandle {
+    store: MemoryStore,
+    _stopper: background::Stopper,
+}
+
+impl CleanupHandle {
+    /// Runs the cleanup immediately, without waiting for the next
+    /// interval. This is mostly useful in tests.
+    pub async fn tick(&self) -> Result {
+        self.store.cleanup().await
+    }
+}
+
+/// # A handle to an autosave task of a [`MemoryStore`]
+///
+/// The task is stopped when this handle is dropped. See
+/// `MemoryStore::spawn_autosave` and [`MemoryStore::autosave_task`].
+#[derive(Debug)]
+#[must_use = "the autosave task is stopped when its handle is dropped"]
+pub struct AutosaveHandle {
+    store: MemoryStore,
+    path: PathBuf,
+    _stopper: background::Stopper,
+}
+
+impl AutosaveHandle {
+    /// Saves a snapshot immediately, without waiting for the next
+    /// interval. This can be used to save a final snapshot on
+    /// shutdown.
+    pub async fn tick(&self) -> Result {
+        self.store.save_snapshot(&self.path).await
     }
 }
 
@@ -156,19 +901,230 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn loading_copies_of_sessions() -> Result {
+        let store = MemoryStore::new();
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        session.insert("key", "value")?;
+        let other = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert!(other.get_raw("key").is_none());
+        assert!(!other.data_changed());
+
+        store.store_session(session).await?;
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(2, session.version());
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn comparing_versions_before_storing() -> Result {
+        let store = MemoryStore::new();
+        let session = Session::new();
+        let stale = session.clone();
+        let cookie_value = store.compare_and_store(session).await?.unwrap();
+        let result = store.compare_and_store(stale).await;
+        assert!(matches!(result, Err(SessionError::Conflict)));
+
+        let mut first = store.load_session(cookie_value.clone()).await?.unwrap();
+        let mut second = store.load_session(cookie_value.clone()).await?.unwrap();
+        first.insert("first", true)?;
+        second.insert("second", true)?;
+        store.compare_and_store(first).await?;
+        let result = store.compare_and_store(second).await;
+        assert!(matches!(result, Err(SessionError::Conflict)));
+
+        let mut second = store.load_session(cookie_value.clone()).await?.unwrap();
+        second.insert("second", true)?;
+        store.compare_and_store(second).await?;
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(3, session.version());
+        assert_eq!(Some(true), session.get::<bool>("first"));
+        assert_eq!(Some(true), session.get::<bool>("second"));
+
+        store.destroy_session(session.clone()).await?;
+        let result = store.compare_and_store(session).await;
+        assert!(matches!(result, Err(SessionError::Conflict)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn comparing_regenerated_sessions() -> Result {
+        let store = MemoryStore::new();
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        let mut session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(1, session.version());
+
+        session.regenerate();
+        assert_eq!(0, session.version());
+        let regenerated = session.clone();
+        let cookie_value = store.compare_and_store(session).await?.unwrap();
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(1, session.version());
+        let result = store.compare_and_store(regenerated).await;
+        assert!(matches!(result, Err(SessionError::Conflict)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn mixing_plain_and_compared_stores() -> Result {
+        for update_mode in [UpdateMode::Replace, UpdateMode::Merge] {
+            let store = MemoryStore::new().with_update_mode(update_mode);
+            let session = Session::new();
+            let stale = session.clone();
+            let cookie_value = store.store_session(session).await?.unwrap();
+
+            let loaded = store.load_session(cookie_value.clone()).await?.unwrap();
+            store.store_session(stale.clone()).await?;
+            store.store_session(stale).await?;
+            let session = store.load_session(cookie_value.clone()).await?.unwrap();
+            assert_eq!(3, session.version());
+
+            let result = store.compare_and_store(loaded).await;
+            assert!(matches!(result, Err(SessionError::Conflict)));
+            store.compare_and_store(session).await?;
+            let session = store.load_session(cookie_value).await?.unwrap();
+            assert_eq!(4, session.version());
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn comparing_versions_concurrently() -> Result {
+        let store = MemoryStore::with_shards(4);
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                let cookie_value = cookie_value.clone();
+                task::spawn(async move {
+                    let session = store.load_session(cookie_value).await?.unwrap();
+                    store.compare_and_store(session).await
+                })
+            })
+            .collect();
+
+        let mut stored = 0;
+        for task in tasks {
+            match task.await {
+                Ok(_) => stored += 1,
+                Err(SessionError::Conflict) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(1 + stored, session.version());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn merging_changed_keys() -> Result {
+        let store = MemoryStore::new().with_update_mode(UpdateMode::Merge);
+        let mut session = Session::new();
+        session.insert("kept", 1)?;
+        session.insert("removed", 1)?;
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let mut first = store.load_session(cookie_value.clone()).await?.unwrap();
+        let mut second = store.load_session(cookie_value.clone()).await?.unwrap();
+        first.insert("first", 1)?;
+        first.remove("removed");
+        second.insert("second", 2)?;
+        second.expire_in(Duration::from_secs(60));
+        store.store_session(first).await?;
+        store.store_session(second).await?;
+
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(3, session.version());
+        assert!(session.expiry().is_some());
+        let mut keys: Vec<_> = session.data().into_keys().collect();
+        keys.sort();
+        assert_eq!(vec!["first", "kept", "second"], keys);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn merging_principals_and_expiries() -> Result {
+        let store = MemoryStore::new().with_update_mode(UpdateMode::Merge);
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+
+        let mut first = store.load_session(cookie_value.clone()).await?.unwrap();
+        let mut second = store.load_session(cookie_value.clone()).await?.unwrap();
+        first.set_principal("user");
+        first.expire_in(Duration::from_secs(60));
+        let expiry = *first.expiry().unwrap();
+        second.insert("key", "value")?;
+        store.store_session(first).await?;
+        store.store_session(second).await?;
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(Some("user"), session.principal());
+        assert_eq!(Some(&expiry), session.expiry());
+        assert_eq!(Some("value".to_owned()), session.get::<String>("key"));
+
+        let mut first = store.load_session(cookie_value.clone()).await?.unwrap();
+        let second = store.load_session(cookie_value.clone()).await?.unwrap();
+        first.remove_principal();
+        first.expire_in(Duration::from_secs(120));
+        let expiry = *first.expiry().unwrap();
+        store.store_session(second).await?;
+        store.store_session(first).await?;
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(None, session.principal());
+        assert_eq!(Some(&expiry), session.expiry());
+
+        // reissuing a session does not count as changing its principal
+        let mut first = store.load_session(cookie_value.clone()).await?.unwrap();
+        let second = store.load_session(cookie_value.clone()).await?.unwrap();
+        first.set_principal("other");
+        second.mark_for_reissue();
+        store.store_session(first).await?;
+        store.store_session(second).await?;
+
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(Some("other"), session.principal());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn replacing_expired_sessions_in_merge_mode() -> Result {
+        let store = MemoryStore::new().with_update_mode(UpdateMode::Merge);
+        let mut session = Session::new();
+        session.insert("stale", 1)?;
+        session.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(1));
+        let stale = session.clone();
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let mut session = stale;
+        session.expire_in(Duration::from_secs(60));
+        session.remove("stale");
+        session.reset_data_changed();
+        session.insert("fresh", 1)?;
+        store.store_session(session).await?;
+
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(None, session.get_raw("stale"));
+        assert_eq!(Some(1), session.get::<usize>("fresh"));
+        Ok(())
+    }
+
     #[async_std::test]
     async fn updating_a_session_extending_expiry() -> Result {
         let store = MemoryStore::new();
         let mut session = Session::new();
         session.expire_in(Duration::from_secs(1));
-        let original_expires = session.expiry().unwrap().clone();
+        let original_expires = *session.expiry().unwrap();
         let cookie_value = store.store_session(session).await?.unwrap();
 
         let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
 
         assert_eq!(session.expiry().unwrap(), &original_expires);
         session.expire_in(Duration::from_secs(3));
-        let new_expires = session.expiry().unwrap().clone();
+        let new_expires = *session.expiry().unwrap();
         assert_eq!(None, store.store_session(session).await?);
 
         let session = store.load_session(cookie_value.clone()).await?.unwrap();
@@ -221,6 +1177,51 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn evicting_the_least_recently_used_session() -> Result {
+        let store = MemoryStore::with_capacity(3);
+        let mut cookies = vec![];
+        for _ in 0..3i8 {
+            cookies.push(store.store_session(Session::new()).await?.unwrap());
+        }
+
+        // using the first session makes the second one the least recently used
+        store.load_session(cookies[0].clone()).await?.unwrap();
+        let fourth = store.store_session(Session::new()).await?.unwrap();
+        assert_eq!(3, store.count().await);
+        assert!(store.load_session(cookies[1].clone()).await?.is_none());
+
+        // storing an existing session again does not evict anything
+        let session = store.load_session(cookies[2].clone()).await?.unwrap();
+        store.store_session(session).await?;
+        assert_eq!(3, store.count().await);
+
+        for cookie in [&cookies[0], &cookies[2], &fourth] {
+            assert!(store.load_session(cookie.clone()).await?.is_some());
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn evicting_expired_sessions_first() -> Result {
+        let store = MemoryStore::with_capacity(3);
+        let mut expired = Session::new();
+        expired.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(expired.clone()).await?;
+        store.store_session(expired).await?;
+
+        let oldest = store.store_session(Session::new()).await?.unwrap();
+        let mut expired = Session::new();
+        expired.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(expired).await?;
+
+        store.store_session(Session::new()).await?;
+        store.store_session(Session::new()).await?;
+        assert_eq!(3, store.count().await);
+        assert!(store.load_session(oldest).await?.is_some());
+        Ok(())
+    }
+
     #[async_std::test]
     async fn clearing_the_whole_store() -> Result {
         let store = MemoryStore::new();
@@ -234,4 +1235,423 @@ mod tests {
 
         Ok(())
     }
+
+    #[async_std::test]
+    async fn clearing_sessions_by_prefix() -> Result {
+        let store = MemoryStore::with_shards(4);
+        for prefix in ["app.", "other."] {
+            for _ in 0..8i8 {
+                let mut session = Session::new();
+                session.set_id(format!("{}{}", prefix, session.id()));
+                session.expire_in(Duration::from_secs(60));
+                store.store_session(session).await?;
+            }
+        }
+
+        store.clear_prefix("app.").await?;
+        assert_eq!(8, store.count().await);
+        for shard in store.shards.iter() {
+            let sessions = shard.sessions.read().await;
+            assert!(sessions.entries.keys().all(|id| id.starts_with("other.")));
+            assert_eq!(sessions.entries.len(), sessions.expiries.len());
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn sharding_a_store() -> Result {
+        let store = MemoryStore::with_shards(4);
+        let mut cookies = vec![];
+        for _ in 0..64i8 {
+            cookies.push(store.store_session(Session::new()).await?.unwrap());
+        }
+        store_expired(&store).await?;
+        assert_eq!(65, store.count().await);
+        for shard in store.shards.iter() {
+            assert!(!shard.sessions.read().await.entries.is_empty());
+        }
+
+        for cookie in &cookies {
+            assert!(store.load_session(cookie.clone()).await?.is_some());
+        }
+        let session = store.load_session(cookies[0].clone()).await?.unwrap();
+        store.destroy_session(session).await?;
+        assert_eq!(64, store.count().await);
+
+        store.cleanup().await?;
+        assert_eq!(63, store.count().await);
+        store.clear_store().await?;
+        assert_eq!(0, store.count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn dividing_capacity_between_shards() -> Result {
+        let store = MemoryStore::with_capacity_and_shards(10, 4);
+        let capacities: Vec<_> = store.shards.iter().map(|shard| shard.capacity).collect();
+        assert_eq!(vec![Some(3), Some(3), Some(2), Some(2)], capacities);
+
+        for _ in 0..64i8 {
+            store.store_session(Session::new()).await?;
+        }
+        assert_eq!(10, store.count().await);
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejecting_fewer_sessions_than_shards() {
+        MemoryStore::with_capacity_and_shards(3, 4);
+    }
+
+    #[async_std::test]
+    async fn restoring_a_snapshot() -> Result {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions");
+        let store = MemoryStore::with_shards(4);
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        session.expire_in(Duration::from_secs(60));
+        let expiry = *session.expiry().unwrap();
+        let cookie_value = store.store_session(session).await?.unwrap();
+        store_expired(&store).await?;
+
+        store.save_snapshot(&path).await?;
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o777);
+        }
+
+        // a session that expires between saving and restoring
+        let contents = fs::read(&path).unwrap();
+        let mut records: Vec<Vec<u8>> =
+            bincode::deserialize(contents.strip_prefix(SNAPSHOT_MAGIC).unwrap())?;
+        let mut expired = Session::new();
+        expired.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(1));
+        records.push(Format::new(Bincode).encode_record(&expired)?);
+        let mut contents = SNAPSHOT_MAGIC.to_vec();
+        contents.extend(bincode::serialize(&records)?);
+        fs::write(&path, contents).unwrap();
+
+        let restarted = MemoryStore::new();
+        restarted.load_snapshot(&path).await?;
+        assert_eq!(1, restarted.count().await);
+        let session = restarted.load_session(cookie_value).await?.unwrap();
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        assert_eq!(Some(&expiry), session.expiry());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn loading_a_missing_or_invalid_snapshot() -> Result {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions");
+        let store = MemoryStore::new();
+        store.load_snapshot(&path).await?;
+        assert_eq!(0, store.count().await);
+
+        fs::write(&path, b"something else").unwrap();
+        assert!(matches!(
+            store.load_snapshot(&path).await,
+            Err(SessionError::Serialization(_))
+        ));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn autosaving_snapshots() -> Result {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions");
+        let store = MemoryStore::new();
+        let (autosave, _) = store.autosave_task(&path, Duration::from_secs(3600), task::sleep);
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        autosave.tick().await?;
+
+        let restarted = MemoryStore::new();
+        restarted.load_snapshot(&path).await?;
+        assert!(restarted.load_session(cookie_value).await?.is_some());
+
+        let (clock, sleep) = clock();
+        let (autosave, autosave_task) =
+            restarted.autosave_task(&path, Duration::from_secs(3600), sleep);
+        let autosave_task = task::spawn(autosave_task);
+        restarted.clear_store().await?;
+        clock.advance().await;
+        drop(autosave);
+        autosave_task.await;
+
+        let restarted = MemoryStore::new();
+        restarted.load_snapshot(&path).await?;
+        assert_eq!(0, restarted.count().await);
+        Ok(())
+    }
+
+    /// lets a background task run one interval at a time instead of
+    /// waiting for real time to pass
+    struct Clock {
+        wake: async_channel::Sender<()>,
+        asleep: async_channel::Receiver<()>,
+    }
+
+    impl Clock {
+        /// lets the task run once and waits until it sleeps again
+        async fn advance(&self) {
+            self.wake.send(()).await.unwrap();
+            self.asleep.recv().await.unwrap();
+        }
+    }
+
+    /// a clock along with the sleep function to hand to the task
+    fn clock() -> (
+        Clock,
+        impl Fn(Duration) -> futures_lite::future::Boxed<()> + Send + 'static,
+    ) {
+        use futures_lite::FutureExt;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let (wake, woken) = async_channel::unbounded();
+        let (sleeping, asleep) = async_channel::unbounded();
+        let started = AtomicBool::new(false);
+        let sleep = move |_| {
+            let (woken, sleeping) = (woken.clone(), sleeping.clone());
+            let ran = started.swap(true, Ordering::SeqCst);
+            async move {
+                if ran {
+                    let _ = sleeping.send(()).await;
+                }
+                let _ = woken.recv().await;
+            }
+            .boxed()
+        };
+        (Clock { wake, asleep }, sleep)
+    }
+
+    async fn store_expired(store: &MemoryStore) -> Result {
+        let mut session = Session::new();
+        session.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(session).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn listing_sessions_in_pages() -> Result {
+        let store = MemoryStore::with_shards(4);
+        assert!(crate::session_info::list_ids(&store, 3).await?.is_empty());
+
+        let mut ids = vec![];
+        for _ in 0..7i8 {
+            let session = Session::new();
+            ids.push(session.id().to_owned());
+            store.store_session(session).await?;
+        }
+        store_expired(&store).await?;
+        ids.sort();
+
+        assert_eq!(ids, crate::session_info::list_ids(&store, 3).await?);
+        let page = store.list_sessions(Some(&ids[4]), 3).await?;
+        assert_eq!(2, page.sessions().len());
+        assert_eq!(None, page.next());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn listing_sessions_by_prefix() -> Result {
+        let store = MemoryStore::with_shards(4);
+        let mut ids = vec![];
+        for prefix in ["a.", "b.", "b.", "b.", "c."] {
+            let mut session = Session::new();
+            session.set_id(format!("{}{}", prefix, session.id()));
+            if prefix == "b." {
+                ids.push(session.id().to_owned());
+            }
+            store.store_session(session).await?;
+        }
+        ids.sort();
+
+        let page = store.list_prefix("b.", None, 2).await?;
+        let listed: Vec<_> = page.sessions().iter().map(|info| info.id()).collect();
+        assert_eq!(ids[..2], listed);
+        let page = store.list_prefix("b.", page.next(), 2).await?;
+        assert_eq!(ids[2], page.sessions()[0].id());
+        assert_eq!(None, page.next());
+
+        let page = store.list_prefix("b.", Some("a.z"), 5).await?;
+        assert_eq!(3, page.sessions().len());
+        let page = store.list_prefix("b.", Some("c."), 5).await?;
+        assert!(page.sessions().is_empty());
+        assert!(store
+            .list_prefix("d.", None, 5)
+            .await?
+            .sessions()
+            .is_empty());
+        Ok(())
+    }
+
+    fn principal_session(principal: &str) -> Session {
+        let mut session = Session::new();
+        session.set_principal(principal);
+        session
+    }
+
+    async fn indexed(store: &MemoryStore) -> usize {
+        let mut indexed = 0;
+        for shard in store.shards.iter() {
+            let sessions = shard.sessions.read().await;
+            indexed += sessions
+                .principals
+                .values()
+                .map(BTreeSet::len)
+                .sum::<usize>();
+        }
+        indexed
+    }
+
+    #[async_std::test]
+    async fn indexing_sessions_by_principal() -> Result {
+        let store = MemoryStore::with_shards(4);
+        let mut ids = vec![];
+        for _ in 0..4i8 {
+            let session = principal_session("user");
+            ids.push(session.id().to_owned());
+            store.store_session(session).await?;
+        }
+        store.store_session(principal_session("other")).await?;
+        store.store_session(Session::new()).await?;
+        let mut expired = principal_session("user");
+        expired.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(expired).await?;
+        ids.sort();
+
+        let listed = store.list_principal_sessions("user").await?;
+        let listed: Vec<_> = listed.iter().map(|info| info.id().to_owned()).collect();
+        assert_eq!(ids, listed);
+        assert!(store.list_principal_sessions("nobody").await?.is_empty());
+        assert_eq!(6, indexed(&store).await);
+
+        store.cleanup().await?;
+        assert_eq!(5, indexed(&store).await);
+
+        store.destroy_principal_sessions("user").await?;
+        assert!(store.list_principal_sessions("user").await?.is_empty());
+        assert_eq!(2, store.count().await);
+        assert_eq!(1, indexed(&store).await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn keeping_the_principal_index_consistent() -> Result {
+        let store = MemoryStore::with_capacity(2);
+        let cookie_value = store
+            .store_session(principal_session("user"))
+            .await?
+            .unwrap();
+
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        session.set_principal("other");
+        store.store_session(session).await?;
+        assert!(store.list_principal_sessions("user").await?.is_empty());
+        assert_eq!(1, store.list_principal_sessions("other").await?.len());
+
+        let mut session = store.load_session(cookie_value).await?.unwrap();
+        session.remove_principal();
+        store.store_session(session.clone()).await?;
+        assert_eq!(0, indexed(&store).await);
+
+        store.store_session(principal_session("user")).await?;
+        store.destroy_session(session).await?;
+        assert_eq!(1, indexed(&store).await);
+        for _ in 0..2i8 {
+            store.store_session(Session::new()).await?;
+        }
+        assert_eq!(0, indexed(&store).await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn cleaning_up_only_expired_sessions() -> Result {
+        let store = MemoryStore::new();
+        store_expired(&store).await?;
+        store.store_session(Session::new()).await?;
+
+        let mut extended = Session::new();
+        extended.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(extended.clone()).await?;
+        extended.expire_in(Duration::from_secs(60));
+        let extended = store.store_session(extended).await?.unwrap();
+
+        let mut destroyed = Session::new();
+        destroyed.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(destroyed.clone()).await?;
+        store.destroy_session(destroyed).await?;
+
+        assert_eq!(3, store.count().await);
+        store.cleanup().await?;
+        assert_eq!(2, store.count().await);
+        assert!(store.load_session(extended).await?.is_some());
+        assert_eq!(1, store.shards[0].sessions.read().await.expiries.len());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn cleaning_up_in_the_background() -> Result {
+        let store = MemoryStore::new();
+        store_expired(&store).await?;
+        store.store_session(Session::new()).await?;
+
+        let (clock, sleep) = clock();
+        let (cleanup, cleanup_task) = store.cleanup_task(Duration::from_secs(3600), sleep);
+        let cleanup_task = task::spawn(cleanup_task);
+        clock.advance().await;
+        assert_eq!(1, store.count().await);
+
+        drop(cleanup);
+        cleanup_task.await;
+        store_expired(&store).await?;
+        assert_eq!(2, store.count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn cleaning_up_on_a_manual_tick() -> Result {
+        let store = MemoryStore::new();
+        let (cleanup, _) = store.cleanup_task(Duration::from_secs(3600), task::sleep);
+        store_expired(&store).await?;
+        assert_eq!(1, store.count().await);
+
+        cleanup.tick().await?;
+        assert_eq!(0, store.count().await);
+        Ok(())
+    }
+
+    #[cfg(feature = "async-std")]
+    #[async_std::test]
+    async fn spawning_a_cleanup_task_on_async_std() -> Result {
+        let store = MemoryStore::new();
+        let _cleanup = store.spawn_cleanup(Duration::from_millis(10));
+        store_expired(&store).await?;
+        task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(0, store.count().await);
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn spawning_a_cleanup_task_on_tokio() -> Result {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let store = MemoryStore::new();
+            let _cleanup = store.spawn_cleanup(Duration::from_millis(10));
+            store_expired(&store).await?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(0, store.count().await);
+            Ok(())
+        })
+    }
 }
@@ -0,0 +1,224 @@
+use crate::{async_trait, log, session_store::Lookup, Result, Session, SessionInfo, SessionStore};
+use std::collections::HashSet;
+
+/// # A session store that migrates sessions between two stores
+///
+/// Moves sessions from an old store to a new one without logging
+/// users out. Sessions are loaded from the new store if possible, and
+/// are otherwise loaded from the old store and copied to the new one.
+/// Sessions are only ever stored in the new store, while destroying a
+/// session or clearing the store affects both stores. The migration
+/// therefore completes gradually as users come back, and the old
+/// store can be removed once every session in it has expired.
+///
+/// Both stores must derive session ids from cookie values in the same
+/// way, like every store in this crate that stores sessions on the
+/// server does, so that the cookie that a client already has also
+/// identifies its session in the new store.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{FileStore, MemoryStore, MigratingStore, Session, SessionStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// # let dir = tempfile::tempdir().unwrap();
+/// let old = MemoryStore::new();
+/// let cookie_value = old.store_session(Session::new()).await?.unwrap();
+///
+/// let store = MigratingStore::new(old, FileStore::new(dir.path()));
+/// assert!(store.load_session(cookie_value.clone()).await?.is_some());
+/// assert!(store.new_store().load_session(cookie_value).await?.is_some());
+/// # Ok(()) }) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MigratingStore<Old, New> {
+    old: Old,
+    new: New,
+}
+
+impl<Old, New> MigratingStore<Old, New>
+where
+    Old: SessionStore + Send + Sync,
+    New: SessionStore + Send + Sync,
+{
+    /// constructs a new MigratingStore that migrates sessions from
+    /// the old store to the new one
+    pub fn new(old: Old, new: New) -> Self {
+        Self { old, new }
+    }
+
+    /// the store that sessions are migrated from
+    pub fn old_store(&self) -> &Old {
+        &self.old
+    }
+
+    /// the store that sessions are migrated to
+    pub fn new_store(&self) -> &New {
+        &self.new
+    }
+
+    /// loads a session from the new store, or from the old store
+    /// after which it is migrated to the new store
+    async fn load(&self, lookup: Lookup<'_>) -> Result<Option<Session>> {
+        if let Some(session) = lookup.load(&self.new).await? {
+            return Ok(Some(session));
+        }
+
+        match lookup.load(&self.old).await? {
+            Some(session) => {
+                log::trace!("migrating session `{}` to the new store", session.id());
+                // storing the session itself would reset its changes
+                // and reissue flag, which the caller still needs
+                self.new.store_session(session.snapshot()).await?;
+                Ok(Some(session))
+            }
+
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl<Old, New> SessionStore for MigratingStore<Old, New>
+where
+    Old: SessionStore + Send + Sync,
+    New: SessionStore + Send + Sync,
+{
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        self.load(Lookup::CookieValue(&cookie_value)).await
+    }
+
+    async fn load_session_by_id(&self, id: &str) -> Result<Option<Session>> {
+        self.load(Lookup::Id(id)).await
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        self.new.store_session(session).await
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        self.new.destroy_session(session.clone()).await?;
+        self.old.destroy_session(session).await
+    }
+
+    async fn clear_store(&self) -> Result {
+        self.new.clear_store().await?;
+        self.old.clear_store().await
+    }
+
+    async fn clear_prefix(&self, prefix: &str) -> Result {
+        self.new.clear_prefix(prefix).await?;
+        self.old.clear_prefix(prefix).await
+    }
+
+    /// Sessions that are in both stores are listed as they are in the
+    /// new store.
+    async fn list_principal_sessions(&self, principal: &str) -> Result<Vec<SessionInfo>> {
+        let mut sessions = self.new.list_principal_sessions(principal).await?;
+        let migrated: HashSet<String> = sessions.iter().map(|info| info.id().to_owned()).collect();
+        let old = self.old.list_principal_sessions(principal).await?;
+        sessions.extend(old.into_iter().filter(|info| !migrated.contains(info.id())));
+        sessions.sort_unstable_by(|a, b| a.id().cmp(b.id()));
+        Ok(sessions)
+    }
+
+    async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+        self.new.destroy_principal_sessions(principal).await?;
+        self.old.destroy_principal_sessions(principal).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncryptedStore, Keyring, MemoryStore};
+
+    fn store() -> MigratingStore<MemoryStore, MemoryStore> {
+        MigratingStore::new(MemoryStore::new(), MemoryStore::new())
+    }
+
+    #[async_std::test]
+    async fn migrating_sessions_on_load() -> Result {
+        let store = store();
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let cookie_value = store.old_store().store_session(session).await?.unwrap();
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        assert_eq!(1, store.new_store().count().await);
+
+        let session = store.new_store().load_session(cookie_value).await?.unwrap();
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn keeping_the_flags_of_migrated_sessions() -> Result {
+        let inner = MemoryStore::new();
+        let old = EncryptedStore::new(inner.clone(), &[1; 32]);
+        let cookie_value = old.store_session(Session::new()).await?.unwrap();
+
+        let old = EncryptedStore::new(inner, Keyring::new(&[2; 32]).with_retired(&[1; 32]));
+        let store = MigratingStore::new(old, MemoryStore::new());
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert!(session.should_reissue());
+        assert!(session.data_changed());
+        assert_eq!(1, store.new_store().count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn storing_only_in_the_new_store() -> Result {
+        let store = store();
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        assert!(store.load_session(cookie_value).await?.is_some());
+        assert_eq!(1, store.new_store().count().await);
+        assert_eq!(0, store.old_store().count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn destroying_in_both_stores() -> Result {
+        let store = store();
+        let cookie_value = store
+            .old_store()
+            .store_session(Session::new())
+            .await?
+            .unwrap();
+        for _ in 0..3i8 {
+            store.old_store().store_session(Session::new()).await?;
+        }
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        store.destroy_session(session).await?;
+        assert!(store.load_session(cookie_value).await?.is_none());
+        assert_eq!(0, store.new_store().count().await);
+        assert_eq!(3, store.old_store().count().await);
+
+        store.clear_store().await?;
+        assert_eq!(0, store.old_store().count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn finding_sessions_by_principal_in_both_stores() -> Result {
+        let store = store();
+        let mut cookie_values = vec![];
+        for _ in 0..2i8 {
+            let mut session = Session::new();
+            session.set_principal("user");
+            cookie_values.push(store.old_store().store_session(session).await?.unwrap());
+        }
+        store.load_session(cookie_values[0].clone()).await?;
+        let mut session = Session::new();
+        session.set_principal("user");
+        store.store_session(session).await?;
+
+        assert_eq!(3, store.list_principal_sessions("user").await?.len());
+        store.destroy_principal_sessions("user").await?;
+        assert!(store.list_principal_sessions("user").await?.is_empty());
+        assert_eq!(0, store.old_store().count().await);
+        Ok(())
+    }
+}
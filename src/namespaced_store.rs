@@ -0,0 +1,415 @@
+use crate::{async_trait, log, Result, Session, SessionInfo, SessionPage, SessionStore};
+
+/// # A session store that scopes sessions to a namespace
+///
+/// Lets several applications or tenants share one session store
+/// without seeing each other's sessions. Every session id is prefixed
+/// with the namespace and a `.` before it reaches the inner store, and
+/// sessions are loaded from the inner store with
+/// [`SessionStore::load_session_by_id`], by the id that is derived
+/// from their cookie value and prefixed in the same way, so that the
+/// same cookie value identifies a different session in every
+/// namespace. The principals of sessions are prefixed with the length
+/// of the namespace, a `:`, the namespace and a `.`, such as
+/// `5:admin.user`, so that
+/// [`SessionStore::destroy_principal_sessions`] only affects this
+/// namespace even if principals contain a `.` or namespaces are
+/// nested. Sessions that are loaded through this store have their id
+/// and principal without the prefix, so wrapping a store in a
+/// namespace is invisible to the application.
+///
+/// [`SessionStore::clear_store`] only destroys the sessions of this
+/// namespace, with [`SessionStore::clear_prefix`], so the inner store
+/// has to support that as well as loading sessions by id. All
+/// server-side stores in this crate do, while the
+/// [`CookieStore`](crate::CookieStore) can not be namespaced. Every
+/// application that shares the inner store should use its own
+/// namespace, because sessions that are stored without one can not be
+/// told apart from the sessions of other namespaces.
+///
+/// Listing the sessions of a namespace with
+/// [`SessionStore::list_sessions`] only visits the sessions of other
+/// namespaces if the inner store does not list sessions in the order
+/// of their ids, like the [`FileStore`](crate::FileStore).
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{MemoryStore, NamespacedStore, Session, SessionStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// let shared = MemoryStore::new();
+/// let blog = NamespacedStore::new(shared.clone(), "blog");
+/// let shop = NamespacedStore::new(shared.clone(), "shop");
+///
+/// let cookie_value = blog.store_session(Session::new()).await?.unwrap();
+/// shop.store_session(Session::new()).await?;
+/// assert!(shop.load_session(cookie_value.clone()).await?.is_none());
+///
+/// shop.clear_store().await?;
+/// assert!(blog.load_session(cookie_value).await?.is_some());
+/// assert_eq!(1, shared.count().await);
+/// # Ok(()) }) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct NamespacedStore<S> {
+    inner: S,
+    prefix: String,
+    principal_prefix: String,
+}
+
+impl<S: SessionStore + Send + Sync> NamespacedStore<S> {
+    /// constructs a new NamespacedStore that stores sessions in the
+    /// inner store under the provided namespace
+    ///
+    /// # Panics
+    ///
+    /// Panics if the namespace is empty or contains a `.`, so that no
+    /// namespace is a prefix of another.
+    pub fn new(inner: S, namespace: impl AsRef<str>) -> Self {
+        let namespace = namespace.as_ref();
+        assert!(
+            !namespace.is_empty() && !namespace.contains('.'),
+            "session namespaces must be non-empty and must not contain a `.`"
+        );
+
+        Self {
+            inner,
+            prefix: format!("{}.", namespace),
+            principal_prefix: format!("{}:{}.", namespace.len(), namespace),
+        }
+    }
+
+    /// the namespace that sessions are stored under
+    pub fn namespace(&self) -> &str {
+        &self.prefix[..self.prefix.len() - 1]
+    }
+
+    /// the store that the namespaced sessions are stored in
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// prefixes the session id and principal with the namespace
+    fn scope(&self, mut session: Session) -> Session {
+        let id = format!("{}{}", self.prefix, session.id());
+        session.set_id(id);
+        let principal = session
+            .principal()
+            .map(|principal| self.scope_principal(principal));
+        session.with_principal(principal)
+    }
+
+    fn scope_principal(&self, principal: &str) -> String {
+        format!("{}{}", self.principal_prefix, principal)
+    }
+
+    /// removes the prefixes that [`NamespacedStore::scope`] added,
+    /// returning `None` if the session does not have them
+    fn unscope(&self, mut session: Session) -> Option<Session> {
+        let id = session.id().strip_prefix(&self.prefix)?.to_owned();
+        let principal = match session.principal() {
+            Some(principal) => Some(principal.strip_prefix(&self.principal_prefix)?.to_owned()),
+            None => None,
+        };
+        session.set_id(id);
+        Some(session.with_principal(principal))
+    }
+}
+
+#[async_trait]
+impl<S: SessionStore + Send + Sync> SessionStore for NamespacedStore<S> {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        self.load_session_by_id(&id).await
+    }
+
+    async fn load_session_by_id(&self, id: &str) -> Result<Option<Session>> {
+        let id = format!("{}{}", self.prefix, id);
+        let session = match self.inner.load_session_by_id(&id).await? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+
+        let id = session.id().to_owned();
+        match self.unscope(session) {
+            Some(session) => Ok(Some(session)),
+            None => {
+                log::debug!(
+                    "ignoring session `{}` outside of namespace `{}`",
+                    id,
+                    self.namespace()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        self.inner.store_session(self.scope(session)).await
+    }
+
+    async fn compare_and_store(&self, session: Session) -> Result<Option<String>> {
+        self.inner.compare_and_store(self.scope(session)).await
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        self.inner.destroy_session(self.scope(session)).await
+    }
+
+    async fn clear_store(&self) -> Result {
+        log::trace!("clearing namespace `{}`", self.namespace());
+        self.inner.clear_prefix(&self.prefix).await
+    }
+
+    async fn clear_prefix(&self, prefix: &str) -> Result {
+        let prefix = format!("{}{}", self.prefix, prefix);
+        self.inner.clear_prefix(&prefix).await
+    }
+
+    /// Sessions are listed from the inner store with
+    /// [`SessionStore::list_prefix`], with the cursors of the inner
+    /// store.
+    async fn list_sessions(&self, cursor: Option<&str>, limit: usize) -> Result<SessionPage> {
+        self.list_prefix("", cursor, limit).await
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<SessionPage> {
+        let prefix = format!("{}{}", self.prefix, prefix);
+        let page = self.inner.list_prefix(&prefix, cursor, limit).await?;
+        let next = page.next().map(str::to_owned);
+        let sessions = page
+            .into_sessions()
+            .into_iter()
+            .filter_map(|info| info.strip_prefix(&self.prefix, &self.principal_prefix))
+            .collect();
+        Ok(SessionPage::new(sessions, next))
+    }
+
+    async fn list_principal_sessions(&self, principal: &str) -> Result<Vec<SessionInfo>> {
+        let principal = self.scope_principal(principal);
+        let sessions = self.inner.list_principal_sessions(&principal).await?;
+        Ok(sessions
+            .into_iter()
+            .filter_map(|info| info.strip_prefix(&self.prefix, &self.principal_prefix))
+            .collect())
+    }
+
+    async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+        let principal = self.scope_principal(principal);
+        self.inner.destroy_principal_sessions(&principal).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CachedStore, EncryptedStore, MemoryStore, SessionError};
+
+    fn stores() -> (
+        MemoryStore,
+        NamespacedStore<MemoryStore>,
+        NamespacedStore<MemoryStore>,
+    ) {
+        let shared = MemoryStore::new();
+        let first = NamespacedStore::new(shared.clone(), "first");
+        let second = NamespacedStore::new(shared.clone(), "second");
+        (shared, first, second)
+    }
+
+    #[async_std::test]
+    async fn storing_and_loading_a_session() -> Result {
+        let (shared, store, _) = stores();
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        session.set_principal("user");
+        let id = session.id().to_owned();
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(id, session.id());
+        assert_eq!(Some("user"), session.principal());
+        assert!(!session.data_changed());
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        assert!(shared.load_session(cookie_value.clone()).await?.is_none());
+        let namespaced = format!("first.{}", cookie_value);
+        assert!(shared.load_session(namespaced).await.is_err());
+
+        session.insert("key", "other value")?;
+        store.store_session(session).await?;
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!("other value", session.get::<String>("key").unwrap());
+        assert_eq!(1, shared.count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn isolating_namespaces() -> Result {
+        let (_, first, second) = stores();
+        let cookie_value = first.store_session(Session::new()).await?.unwrap();
+        assert!(second.load_session(cookie_value.clone()).await?.is_none());
+
+        let session = first.load_session(cookie_value.clone()).await?.unwrap();
+        second.destroy_session(session).await?;
+        assert!(first.load_session(cookie_value).await?.is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn destroying_and_clearing_a_namespace() -> Result {
+        let (shared, first, second) = stores();
+        let cookie_value = first.store_session(Session::new()).await?.unwrap();
+        for _ in 0..3i8 {
+            first.store_session(Session::new()).await?;
+            second.store_session(Session::new()).await?;
+        }
+        shared.store_session(Session::new()).await?;
+
+        let session = first.load_session(cookie_value.clone()).await?.unwrap();
+        first.destroy_session(session).await?;
+        assert!(first.load_session(cookie_value).await?.is_none());
+        assert_eq!(7, shared.count().await);
+
+        first.clear_store().await?;
+        assert_eq!(4, shared.count().await);
+        second.clear_store().await?;
+        assert_eq!(1, shared.count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn listing_a_namespace() -> Result {
+        let (_, first, second) = stores();
+        let mut ids = vec![];
+        for _ in 0..5i8 {
+            let session = Session::new();
+            ids.push(session.id().to_owned());
+            first.store_session(session).await?;
+            second.store_session(Session::new()).await?;
+        }
+
+        let mut listed = crate::session_info::list_ids(&first, 2).await?;
+        ids.sort();
+        listed.sort();
+        assert_eq!(ids, listed);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn scoping_principals() -> Result {
+        let (shared, first, second) = stores();
+        for store in [&first, &second] {
+            let mut session = Session::new();
+            session.set_principal("user");
+            store.store_session(session).await?;
+        }
+
+        let listed = first.list_principal_sessions("user").await?;
+        assert_eq!(1, listed.len());
+        assert_eq!(Some("user"), listed[0].principal());
+        assert_eq!(
+            1,
+            shared.list_principal_sessions("5:first.user").await?.len()
+        );
+
+        first.destroy_principal_sessions("user").await?;
+        assert!(first.list_principal_sessions("user").await?.is_empty());
+        assert_eq!(1, second.list_principal_sessions("user").await?.len());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn telling_principals_of_nested_namespaces_apart() -> Result {
+        let shared = MemoryStore::new();
+        let outer = NamespacedStore::new(shared.clone(), "a");
+        let nested = NamespacedStore::new(outer.clone(), "b");
+        let mut session = Session::new();
+        session.set_principal("b.c");
+        outer.store_session(session).await?;
+        let mut session = Session::new();
+        session.set_principal("c");
+        nested.store_session(session).await?;
+
+        outer.destroy_principal_sessions("b.c").await?;
+        assert_eq!(1, shared.count().await);
+        assert_eq!(1, nested.list_principal_sessions("c").await?.len());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn ignoring_sessions_with_foreign_principals() -> Result {
+        let (shared, store, _) = stores();
+        let mut session = Session::new();
+        session.set_principal("user");
+        let id = format!("first.{}", session.id());
+        session.set_id(id);
+        let cookie_value = shared.store_session(session).await?.unwrap();
+        assert!(store.load_session(cookie_value).await?.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn nesting_namespaces() -> Result {
+        let shared = MemoryStore::new();
+        let outer = NamespacedStore::new(NamespacedStore::new(shared.clone(), "tenant"), "app");
+        let cookie_value = outer.store_session(Session::new()).await?.unwrap();
+        assert!(outer.load_session(cookie_value).await?.is_some());
+
+        outer.inner().store_session(Session::new()).await?;
+        outer.clear_store().await?;
+        assert_eq!(1, shared.count().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn namespacing_wrapped_stores() -> Result {
+        let shared = MemoryStore::new();
+        let slow = EncryptedStore::new(shared.clone(), &[1; 32]);
+        let store = NamespacedStore::new(CachedStore::new(MemoryStore::new(), slow), "app");
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        let cookie_value = store.store_session(session).await?.unwrap();
+        store.inner().fast().clear_store().await?;
+
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!("value", session.get::<String>("key").unwrap());
+        let listed = shared.list_sessions(None, 10).await?;
+        assert_eq!(format!("app.{}", session.id()), listed.sessions()[0].id());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn requiring_prefix_support() {
+        struct Unsupported;
+
+        #[async_trait]
+        impl SessionStore for Unsupported {
+            async fn load_session(&self, _: String) -> Result<Option<Session>> {
+                Ok(None)
+            }
+            async fn store_session(&self, session: Session) -> Result<Option<String>> {
+                Ok(session.into_cookie_value())
+            }
+            async fn destroy_session(&self, _: Session) -> Result {
+                Ok(())
+            }
+            async fn clear_store(&self) -> Result {
+                Ok(())
+            }
+        }
+
+        let store = NamespacedStore::new(Unsupported, "app");
+        let result = store.clear_store().await;
+        assert!(matches!(result, Err(SessionError::Unsupported(_))));
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejecting_nested_namespace_names() {
+        NamespacedStore::new(MemoryStore::new(), "app.admin");
+    }
+}
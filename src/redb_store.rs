@@ -0,0 +1,602 @@
+use crate::{
+    async_trait, envelope::Format, log, session_info::IdRange, Bincode, Result, Session,
+    SessionCodec, SessionError, SessionInfo, SessionPage, SessionStore,
+};
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction};
+use std::{path::Path, sync::Arc};
+use time::OffsetDateTime;
+
+/// sessions by id, along with their expiry as a unix timestamp in
+/// seconds, so that the expiry index can be updated without
+/// deserializing the previous version of a session
+const SESSIONS: TableDefinition<'_, &str, (Option<i64>, &[u8])> =
+    TableDefinition::new("async_sessions");
+
+/// the ids of sessions that have an expiry, ordered by that expiry
+const EXPIRIES: TableDefinition<'_, (i64, &str), ()> =
+    TableDefinition::new("async_session_expiries");
+
+/// the ids of sessions that have a principal, ordered by that
+/// principal
+const PRINCIPALS: TableDefinition<'_, (&str, &str), ()> =
+    TableDefinition::new("async_session_principals");
+
+/// the principal of every session that has one, by id, so that the
+/// principal index can be updated without deserializing the previous
+/// version of a session
+const SESSION_PRINCIPALS: TableDefinition<'_, &str, &str> =
+    TableDefinition::new("async_session_principal_ids");
+
+/// # embedded redb session store
+///
+/// Stores sessions in a [redb](https://docs.rs/redb) database, an
+/// embedded key-value store that is written in pure Rust, so that
+/// sessions survive restarts without running a database server or
+/// linking a C library. This store is only available with the `redb`
+/// cargo feature.
+///
+/// Sessions are stored in one table by id, and a second table orders
+/// the ids of sessions by their expiry, so that
+/// [`RedbStore::cleanup`] only visits sessions that have expired.
+/// Two more tables index sessions by their principal. All tables are
+/// updated in a single transaction whenever a session is stored or
+/// destroyed. Sessions are serialized in the same
+/// versioned format as the [`CookieStore`](crate::CookieStore), with
+/// [`Bincode`] unless another codec is chosen with
+/// [`RedbStore::with_codec`].
+///
+/// redb calls are blocking, so they are run on a thread pool instead
+/// of the async executor.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{RedbStore, Session, SessionStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// # let dir = tempfile::tempdir().unwrap();
+/// let store = RedbStore::open(dir.path().join("sessions.redb")).await?;
+/// let mut session = Session::new();
+/// session.insert("user_id", 1)?;
+/// let cookie_value = store.store_session(session).await?.unwrap();
+///
+/// let session = store.load_session(cookie_value).await?.unwrap();
+/// assert_eq!(session.get::<usize>("user_id"), Some(1));
+/// # Ok(()) }) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RedbStore<C = Bincode> {
+    database: Arc<Database>,
+    format: Format<C>,
+}
+
+impl RedbStore<Bincode> {
+    /// opens the database file at `path`, creating it if it does not
+    /// exist yet
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        log::trace!("opening redb store `{}`", path.display());
+        let database = blocking::unblock(move || Database::create(path)).await?;
+        Self::from_database(database).await
+    }
+
+    /// uses an already opened database, for example one that was
+    /// opened with a custom cache size or storage backend. the
+    /// session tables are created if they do not exist yet.
+    pub async fn from_database(database: Database) -> Result<Self> {
+        let database = Arc::new(database);
+        let store = Self {
+            database,
+            format: Format::new(Bincode),
+        };
+
+        store.write(create_tables).await?;
+        Ok(store)
+    }
+}
+
+impl<C: SessionCodec> RedbStore<C> {
+    /// serializes sessions with the provided codec instead of
+    /// [`Bincode`]. sessions written with any of the built-in codecs
+    /// are recognized on load regardless of this setting.
+    pub fn with_codec<D: SessionCodec>(self, codec: D) -> RedbStore<D> {
+        RedbStore {
+            database: self.database,
+            format: self.format.with_codec(codec),
+        }
+    }
+
+    /// Deletes all expired sessions in a single transaction. This
+    /// should be run on an intermittent basis if this store is run for
+    /// long enough that disk usage is a concern.
+    pub async fn cleanup(&self) -> Result {
+        log::trace!("cleaning up redb store");
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let removed = self
+            .write(move |transaction| {
+                let expired = transaction
+                    .open_table(EXPIRIES)?
+                    .range(..(now, ""))?
+                    .map(|entry| Ok(entry?.0.value().1.to_owned()))
+                    .collect::<Result<Vec<_>>>()?;
+
+                for id in &expired {
+                    remove(transaction, id)?;
+                }
+                Ok(expired.len())
+            })
+            .await?;
+        log::trace!("removed {} expired sessions", removed);
+        Ok(())
+    }
+
+    /// returns the number of sessions in the store, including expired
+    /// sessions that have not been cleaned up yet
+    pub async fn count(&self) -> Result<usize> {
+        let database = self.database.clone();
+        let count = blocking::unblock(move || {
+            let transaction = database.begin_read()?;
+            let sessions = transaction.open_table(SESSIONS)?;
+            Ok::<_, SessionError>(sessions.len()?)
+        })
+        .await?;
+        Ok(count as usize)
+    }
+
+    /// runs a blocking function in a write transaction on a thread
+    /// pool, committing the transaction if the function succeeds
+    async fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&WriteTransaction) -> Result<T> + Send + 'static,
+    {
+        let database = self.database.clone();
+        let result = blocking::unblock(move || {
+            let transaction = database.begin_write()?;
+            let result = f(&transaction)?;
+            transaction.commit()?;
+            Ok::<_, SessionError>(result)
+        })
+        .await?;
+        Ok(result)
+    }
+}
+
+/// creates every table of the store that does not exist yet
+fn create_tables(transaction: &WriteTransaction) -> Result {
+    transaction.open_table(SESSIONS)?;
+    transaction.open_table(EXPIRIES)?;
+    transaction.open_table(PRINCIPALS)?;
+    transaction.open_table(SESSION_PRINCIPALS)?;
+    Ok(())
+}
+
+/// removes a session along with its expiry and principal from the
+/// tables, returning whether there was a session with the provided id
+fn remove(transaction: &WriteTransaction, id: &str) -> Result<bool> {
+    let mut sessions = transaction.open_table(SESSIONS)?;
+    let mut expiries = transaction.open_table(EXPIRIES)?;
+    let mut principals = transaction.open_table(PRINCIPALS)?;
+    let mut session_principals = transaction.open_table(SESSION_PRINCIPALS)?;
+    let expiry = match sessions.remove(id)? {
+        Some(previous) => previous.value().0,
+        None => return Ok(false),
+    };
+
+    if let Some(expiry) = expiry {
+        expiries.remove((expiry, id))?;
+    }
+    if let Some(principal) = session_principals.remove(id)? {
+        principals.remove((principal.value(), id))?;
+    }
+    Ok(true)
+}
+
+/// the ids of the sessions of a principal, ordered by id
+fn principal_ids(transaction: &WriteTransaction, principal: &str) -> Result<Vec<String>> {
+    transaction
+        .open_table(PRINCIPALS)?
+        .range((principal, "")..)?
+        .map(|entry| {
+            let (key, _) = entry?;
+            let (entry_principal, id) = key.value();
+            Ok((entry_principal == principal).then(|| id.to_owned()))
+        })
+        .map_while(Result::transpose)
+        .collect()
+}
+
+#[async_trait]
+impl<C: SessionCodec> SessionStore for RedbStore<C> {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        self.load_session_by_id(&id).await
+    }
+
+    async fn load_session_by_id(&self, id: &str) -> Result<Option<Session>> {
+        log::trace!("loading session by id `{}`", id);
+        let id = id.to_owned();
+        let database = self.database.clone();
+        let record = blocking::unblock(move || {
+            let transaction = database.begin_read()?;
+            let sessions = transaction.open_table(SESSIONS)?;
+            let record = sessions.get(id.as_str())?;
+            Ok::<_, SessionError>(record.map(|record| record.value().1.to_vec()))
+        })
+        .await?;
+
+        match record {
+            Some(record) => Ok(self
+                .format
+                .decode_record(&record)?
+                .and_then(Session::validate)),
+            None => Ok(None),
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        log::trace!("storing session by id `{}`", session.id());
+        let format = self.format.clone();
+        let id = session.id().to_owned();
+        let expiry = session.expiry().map(|expiry| expiry.unix_timestamp());
+        let principal = session.principal().map(str::to_owned);
+        let session = self
+            .write(move |transaction| {
+                let stored = transaction
+                    .open_table(SESSIONS)?
+                    .get(id.as_str())?
+                    .map(|record| record.value().1.to_vec());
+                let mut session = session;
+                session.advance_version(format.record_version(stored.as_deref()));
+                let record = format.encode_record(&session)?;
+
+                remove(transaction, &id)?;
+                let mut sessions = transaction.open_table(SESSIONS)?;
+                sessions.insert(id.as_str(), (expiry, record.as_slice()))?;
+                if let Some(expiry) = expiry {
+                    let mut expiries = transaction.open_table(EXPIRIES)?;
+                    expiries.insert((expiry, id.as_str()), ())?;
+                }
+                if let Some(principal) = &principal {
+                    let mut principals = transaction.open_table(PRINCIPALS)?;
+                    principals.insert((principal.as_str(), id.as_str()), ())?;
+                    let mut session_principals = transaction.open_table(SESSION_PRINCIPALS)?;
+                    session_principals.insert(id.as_str(), principal.as_str())?;
+                }
+                Ok(session)
+            })
+            .await?;
+
+        session.reset_data_changed();
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        log::trace!("destroying session by id `{}`", session.id());
+        let id = session.id().to_owned();
+        self.write(move |transaction| remove(transaction, &id))
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> Result {
+        log::trace!("clearing redb store");
+        self.write(|transaction| {
+            transaction.delete_table(SESSIONS)?;
+            transaction.delete_table(EXPIRIES)?;
+            transaction.delete_table(PRINCIPALS)?;
+            transaction.delete_table(SESSION_PRINCIPALS)?;
+            create_tables(transaction)
+        })
+        .await
+    }
+
+    async fn clear_prefix(&self, prefix: &str) -> Result {
+        log::trace!("clearing sessions with prefix `{}` from redb store", prefix);
+        let prefix = prefix.to_owned();
+        self.write(move |transaction| {
+            let ids = transaction
+                .open_table(SESSIONS)?
+                .range(prefix.as_str()..)?
+                .map(|entry| Ok(entry?.0.value().to_owned()))
+                .take_while(|id: &Result<String>| {
+                    id.as_ref().map_or(true, |id| id.starts_with(&prefix))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            for id in &ids {
+                remove(transaction, id)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sessions are listed in the order of their ids, which are also
+    /// the cursors.
+    async fn list_sessions(&self, cursor: Option<&str>, limit: usize) -> Result<SessionPage> {
+        self.list_prefix("", cursor, limit).await
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<SessionPage> {
+        let range = match IdRange::new(prefix, cursor) {
+            Some(range) => range,
+            None => return Ok(SessionPage::collect(vec![], limit)),
+        };
+        let database = self.database.clone();
+        let records = blocking::unblock(move || {
+            let transaction = database.begin_read()?;
+            let sessions = transaction.open_table(SESSIONS)?;
+            sessions
+                .range::<&str>(range.bounds())?
+                .take(limit.saturating_add(1))
+                .map(|entry| {
+                    let (id, value) = entry?;
+                    Ok((id.value().to_owned(), value.value().1.to_vec()))
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .await?;
+
+        let mut entries = vec![];
+        for (id, record) in records {
+            let session = self
+                .format
+                .decode_record(&record)?
+                .and_then(Session::validate);
+            entries.push((id, session.as_ref().map(SessionInfo::new)));
+        }
+        Ok(SessionPage::collect(entries, limit))
+    }
+
+    async fn list_principal_sessions(&self, principal: &str) -> Result<Vec<SessionInfo>> {
+        let principal = principal.to_owned();
+        let database = self.database.clone();
+        let records = blocking::unblock(move || {
+            let transaction = database.begin_read()?;
+            let principals = transaction.open_table(PRINCIPALS)?;
+            let sessions = transaction.open_table(SESSIONS)?;
+            let mut records = vec![];
+            for entry in principals.range((principal.as_str(), "")..)? {
+                let (key, _) = entry?;
+                let (entry_principal, id) = key.value();
+                if entry_principal != principal {
+                    break;
+                }
+                if let Some(record) = sessions.get(id)? {
+                    records.push(record.value().1.to_vec());
+                }
+            }
+            Ok::<_, SessionError>(records)
+        })
+        .await?;
+
+        let mut sessions = vec![];
+        for record in records {
+            if let Some(session) = self
+                .format
+                .decode_record(&record)?
+                .and_then(Session::validate)
+            {
+                sessions.push(SessionInfo::new(&session));
+            }
+        }
+        Ok(sessions)
+    }
+
+    async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+        log::trace!("destroying sessions of principal `{}`", principal);
+        let principal = principal.to_owned();
+        self.write(move |transaction| {
+            for id in principal_ids(transaction, &principal)? {
+                remove(transaction, &id)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    async fn store() -> Result<(TempDir, RedbStore)> {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RedbStore::open(dir.path().join("sessions.redb")).await?;
+        Ok((dir, store))
+    }
+
+    async fn expiries(store: &RedbStore) -> Result<usize> {
+        let database = store.database.clone();
+        let count = blocking::unblock(move || {
+            let transaction = database.begin_read()?;
+            let expiries = transaction.open_table(EXPIRIES)?;
+            Ok::<_, SessionError>(expiries.len()?)
+        })
+        .await?;
+        Ok(count as usize)
+    }
+
+    #[async_std::test]
+    async fn storing_and_loading_a_session() -> Result {
+        let (_dir, store) = store().await?;
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        session.expire_in(Duration::from_secs(60));
+        let (id, expiry) = (session.id().to_owned(), *session.expiry().unwrap());
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let mut loaded = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(id, loaded.id());
+        assert_eq!(Some(&expiry), loaded.expiry());
+        assert_eq!("value", loaded.get::<String>("key").unwrap());
+
+        loaded.insert("key", "other value")?;
+        loaded.expire_in(Duration::from_secs(120));
+        assert_eq!(None, store.store_session(loaded).await?);
+        let loaded = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!("other value", loaded.get::<String>("key").unwrap());
+        assert_eq!(1, store.count().await?);
+        assert_eq!(1, expiries(&store).await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn loading_missing_and_expired_sessions() -> Result {
+        let (_dir, store) = store().await?;
+        let cookie_value = Session::new().into_cookie_value().unwrap();
+        assert!(store.load_session(cookie_value).await?.is_none());
+
+        let mut session = Session::new();
+        session.expire_in(Duration::from_secs(60));
+        let cookie_value = store.store_session(session).await?.unwrap();
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        session.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(session).await?;
+        assert!(store.load_session(cookie_value).await?.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn versioning_stale_writes() -> Result {
+        let (_dir, store) = store().await?;
+        let session = Session::new();
+        let stale = session.clone();
+        let cookie_value = store.store_session(session).await?.unwrap();
+        store.store_session(stale.clone()).await?;
+        store.store_session(stale).await?;
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(3, session.version());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn destroying_and_clearing_sessions() -> Result {
+        let (_dir, store) = store().await?;
+        let mut session = Session::new();
+        session.expire_in(Duration::from_secs(60));
+        let cookie_value = store.store_session(session).await?.unwrap();
+        for _ in 0..3i8 {
+            store.store_session(Session::new()).await?;
+        }
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        store.destroy_session(session).await?;
+        assert!(store.load_session(cookie_value).await?.is_none());
+        assert_eq!(3, store.count().await?);
+
+        let mut session = Session::new();
+        session.set_id(format!("app.{}", session.id()));
+        session.expire_in(Duration::from_secs(60));
+        store.store_session(session).await?;
+        store.clear_prefix("app.").await?;
+        assert_eq!(3, store.count().await?);
+        assert_eq!(0, expiries(&store).await?);
+
+        store.clear_store().await?;
+        assert_eq!(0, store.count().await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn listing_sessions_in_pages() -> Result {
+        let (_dir, store) = store().await?;
+        let mut ids = vec![];
+        for _ in 0..5i8 {
+            let session = Session::new();
+            ids.push(session.id().to_owned());
+            store.store_session(session).await?;
+        }
+        let mut session = Session::new();
+        session.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(5));
+        store.store_session(session).await?;
+
+        ids.sort();
+        assert_eq!(ids, crate::session_info::list_ids(&store, 2).await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn listing_sessions_by_prefix() -> Result {
+        let (_dir, store) = store().await?;
+        let mut ids = vec![];
+        for prefix in ["a.", "b.", "b.", "c."] {
+            let mut session = Session::new();
+            session.set_id(format!("{}{}", prefix, session.id()));
+            if prefix == "b." {
+                ids.push(session.id().to_owned());
+            }
+            store.store_session(session).await?;
+        }
+        ids.sort();
+
+        let page = store.list_prefix("b.", None, 1).await?;
+        assert_eq!(ids[0], page.sessions()[0].id());
+        let page = store.list_prefix("b.", page.next(), 1).await?;
+        assert_eq!(ids[1], page.sessions()[0].id());
+        assert_eq!(None, page.next());
+        assert!(store
+            .list_prefix("d.", None, 5)
+            .await?
+            .sessions()
+            .is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn indexing_sessions_by_principal() -> Result {
+        let (_dir, store) = store().await?;
+        let mut ids = vec![];
+        for _ in 0..3i8 {
+            let mut session = Session::new();
+            session.set_principal("user");
+            ids.push(session.id().to_owned());
+            store.store_session(session).await?;
+        }
+        let mut session = Session::new();
+        session.set_principal("user");
+        session.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(5));
+        store.store_session(session).await?;
+        let mut session = Session::new();
+        session.set_principal("user-2");
+        store.store_session(session).await?;
+        ids.sort();
+
+        let listed = store.list_principal_sessions("user").await?;
+        let listed: Vec<_> = listed.iter().map(|info| info.id().to_owned()).collect();
+        assert_eq!(ids, listed);
+
+        store.cleanup().await?;
+        store.destroy_principal_sessions("user").await?;
+        assert!(store.list_principal_sessions("user").await?.is_empty());
+        assert_eq!(1, store.list_principal_sessions("user-2").await?.len());
+        assert_eq!(1, store.count().await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn cleaning_up_expired_sessions() -> Result {
+        let (_dir, store) = store().await?;
+        store.store_session(Session::new()).await?;
+        let mut session = Session::new();
+        session.expire_in(Duration::from_secs(60));
+        store.store_session(session).await?;
+        for _ in 0..2i8 {
+            let mut session = Session::new();
+            session.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(5));
+            store.store_session(session).await?;
+        }
+
+        store.cleanup().await?;
+        assert_eq!(2, store.count().await?);
+        assert_eq!(1, expiries(&store).await?);
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::TryFrom,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -20,7 +20,8 @@ use time::OffsetDateTime as DateTime;
 /// and read exactly once in order to set the cookie value.
 ///
 /// ## Change tracking session tracks whether any of its inner data
-/// was changed since it was last serialized. Any session store that
+/// was changed since it was last serialized, and which keys were
+/// changed, see [`Session::changed_keys`]. Any session store that
 /// does not undergo a serialization-deserialization cycle must call
 /// [`Session::reset_data_changed`] in order to reset the change tracker on
 /// an individual record.
@@ -57,15 +58,20 @@ use time::OffsetDateTime as DateTime;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Session {
     id: String,
+    version: u64,
+    created: Option<DateTime>,
     expiry: Option<DateTime>,
+    principal: Option<String>,
     data: Arc<RwLock<HashMap<String, String>>>,
 
     #[serde(skip)]
     cookie_value: Option<String>,
     #[serde(skip)]
-    data_changed: Arc<AtomicBool>,
+    changes: Arc<RwLock<Changes>>,
     #[serde(skip)]
     destroy: Arc<AtomicBool>,
+    #[serde(skip)]
+    reissue: Arc<AtomicBool>,
 }
 
 impl Clone for Session {
@@ -73,14 +79,35 @@ impl Clone for Session {
         Self {
             cookie_value: None,
             id: self.id.clone(),
+            version: self.version,
+            created: self.created,
             data: self.data.clone(),
             expiry: self.expiry,
+            principal: self.principal.clone(),
             destroy: self.destroy.clone(),
-            data_changed: self.data_changed.clone(),
+            changes: self.changes.clone(),
+            reissue: self.reissue.clone(),
         }
     }
 }
 
+/// The changes that were made to a [`Session`] since it was loaded or
+/// last stored.
+#[derive(Debug, Default, Clone)]
+struct Changes {
+    /// the keys that were inserted, updated or removed
+    keys: HashSet<String>,
+    /// whether the principal changed, which also counts as a change
+    /// of the data
+    principal: bool,
+    /// whether the session was marked for reissue, which also counts
+    /// as a change of the data
+    reissued: bool,
+    /// whether the expiry changed, which does not count as a change
+    /// of the data
+    expiry: bool,
+}
+
 impl Default for Session {
     fn default() -> Self {
         Self::new()
@@ -112,12 +139,16 @@ impl Session {
         let id = Session::id_from_cookie_value(&cookie_value).unwrap();
 
         Self {
-            data_changed: Arc::new(AtomicBool::new(false)),
+            changes: Arc::default(),
+            version: 0,
+            created: Some(DateTime::now_utc()),
             expiry: None,
+            principal: None,
             data: Arc::new(RwLock::new(HashMap::default())),
             cookie_value: Some(cookie_value),
             id,
             destroy: Arc::new(AtomicBool::new(false)),
+            reissue: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -172,7 +203,6 @@ impl Session {
     /// session.destroy();
     /// assert!(session.is_destroyed());
     /// # Ok(()) }) }
-
     pub fn is_destroyed(&self) -> bool {
         self.destroy.load(Ordering::SeqCst)
     }
@@ -230,7 +260,7 @@ impl Session {
         let mut data = self.data.write().unwrap();
         if data.get(key) != Some(&value) {
             data.insert(key.to_string(), value);
-            self.data_changed.store(true, Ordering::Release);
+            self.changes.write().unwrap().keys.insert(key.to_string());
         }
     }
 
@@ -281,7 +311,7 @@ impl Session {
     pub fn remove(&mut self, key: &str) {
         let mut data = self.data.write().unwrap();
         if data.remove(key).is_some() {
-            self.data_changed.store(true, Ordering::Release);
+            self.changes.write().unwrap().keys.insert(key.to_string());
         }
     }
 
@@ -314,7 +344,9 @@ impl Session {
         return self.data.read().unwrap().is_empty();
     }
 
-    /// Generates a new id and cookie for this session
+    /// Generates a new id and cookie for this session. The session
+    /// starts over at [version](Session::version) 0, because no
+    /// session with the new id has been stored yet.
     ///
     /// # Example
     ///
@@ -334,6 +366,7 @@ impl Session {
         let cookie_value = generate_cookie(64);
         self.id = Session::id_from_cookie_value(&cookie_value).unwrap();
         self.cookie_value = Some(cookie_value);
+        self.version = 0;
     }
 
     /// sets the cookie value that this session will use to serialize
@@ -356,6 +389,45 @@ impl Session {
         self.cookie_value = Some(cookie_value)
     }
 
+    /// returns how many times this session has been stored. new
+    /// sessions start at version 0, and every write to a session
+    /// store increments the version of the stored session, so that
+    /// [`SessionStore::compare_and_store`](crate::SessionStore::compare_and_store)
+    /// can tell whether a loaded session is still current
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::{MemoryStore, Session, SessionStore};
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let store = MemoryStore::new();
+    /// let session = Session::new();
+    /// assert_eq!(0, session.version());
+    ///
+    /// let cookie_value = store.store_session(session).await?.unwrap();
+    /// let session = store.load_session(cookie_value).await?.unwrap();
+    /// assert_eq!(1, session.version());
+    /// # Ok(()) }) }
+    /// ```
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// returns when this session was created. this is `None` for
+    /// sessions that were stored by a release of this crate that did
+    /// not record creation times yet
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::Session;
+    /// let session = Session::new();
+    /// assert!(session.created().is_some());
+    /// ```
+    pub fn created(&self) -> Option<&DateTime> {
+        self.created.as_ref()
+    }
+
     /// returns the expiry timestamp of this session, if there is one
     ///
     /// # Example
@@ -388,6 +460,66 @@ impl Session {
     /// ```
     pub fn set_expiry(&mut self, expiry: DateTime) {
         self.expiry = Some(expiry);
+        self.changes.write().unwrap().expiry = true;
+    }
+
+    /// returns the principal that this session belongs to, if there
+    /// is one
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::Session;
+    /// let mut session = Session::new();
+    /// assert_eq!(None, session.principal());
+    /// session.set_principal("user-1");
+    /// assert_eq!(Some("user-1"), session.principal());
+    /// ```
+    pub fn principal(&self) -> Option<&str> {
+        self.principal.as_deref()
+    }
+
+    /// associates this session with a principal, such as the id of
+    /// the user that logged in, so that every session of that
+    /// principal can be listed with
+    /// [`SessionStore::list_principal_sessions`](crate::SessionStore::list_principal_sessions)
+    /// and destroyed with
+    /// [`SessionStore::destroy_principal_sessions`](crate::SessionStore::destroy_principal_sessions).
+    /// marks the data as changed if the principal is different.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::Session;
+    /// let mut session = Session::new();
+    /// session.set_principal("user-1");
+    /// assert!(session.data_changed());
+    /// ```
+    pub fn set_principal(&mut self, principal: impl Into<String>) {
+        let principal = principal.into();
+        if self.principal.as_ref() != Some(&principal) {
+            self.principal = Some(principal);
+            self.changes.write().unwrap().principal = true;
+        }
+    }
+
+    /// removes the principal of this session, for example when the
+    /// user logs out without destroying the session. marks the data
+    /// as changed if there was a principal.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::Session;
+    /// let mut session = Session::new();
+    /// session.set_principal("user-1");
+    /// session.remove_principal();
+    /// assert_eq!(None, session.principal());
+    /// ```
+    pub fn remove_principal(&mut self) {
+        if self.principal.take().is_some() {
+            self.changes.write().unwrap().principal = true;
+        }
     }
 
     /// assigns the expiry timestamp to a duration from the current time.
@@ -405,6 +537,7 @@ impl Session {
     /// ```
     pub fn expire_in(&mut self, ttl: std::time::Duration) {
         self.expiry = Some(DateTime::now_utc() + ttl);
+        self.changes.write().unwrap().expiry = true;
     }
 
     /// predicate function to determine if this session is
@@ -479,12 +612,39 @@ impl Session {
     /// # Ok(()) }) }
     /// ```
     pub fn data_changed(&self) -> bool {
-        self.data_changed.load(Ordering::Acquire)
+        let changes = self.changes.read().unwrap();
+        !changes.keys.is_empty() || changes.principal || changes.reissued
     }
 
-    /// Resets `data_changed` dirty tracking. This is unnecessary for
-    /// any session store that serializes the data to a string on
-    /// storage.
+    /// returns the keys that were inserted, updated or removed since
+    /// the session was loaded or last stored, in sorted order. session
+    /// stores that [merge](crate::UpdateMode::Merge) sessions only
+    /// write these keys.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::Session;
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let mut session = Session::new();
+    /// session.insert("b", 1)?;
+    /// session.insert("a", 1)?;
+    /// session.remove("a");
+    /// assert_eq!(vec!["a", "b"], session.changed_keys());
+    ///
+    /// session.reset_data_changed();
+    /// assert!(session.changed_keys().is_empty());
+    /// # Ok(()) }) }
+    /// ```
+    pub fn changed_keys(&self) -> Vec<String> {
+        let mut keys: Vec<_> = self.changes.read().unwrap().keys.iter().cloned().collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Resets `data_changed` dirty tracking, along with any pending
+    /// [`Session::mark_for_reissue`]. This is unnecessary for any
+    /// session store that serializes the data to a string on storage.
     ///
     /// # Example
     ///
@@ -503,7 +663,223 @@ impl Session {
     /// # Ok(()) }) }
     /// ```
     pub fn reset_data_changed(&self) {
-        self.data_changed.store(false, Ordering::SeqCst);
+        *self.changes.write().unwrap() = Changes::default();
+        self.reissue.store(false, Ordering::SeqCst);
+    }
+
+    /// marks this session as needing to be stored again, for example
+    /// because it was loaded with a retired key. this also marks the
+    /// data as changed, so that session middleware stores the session
+    /// at the end of the request. this is generally only performed by
+    /// the session store
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::Session;
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let session = Session::new();
+    /// assert!(!session.should_reissue());
+    /// session.mark_for_reissue();
+    /// assert!(session.should_reissue());
+    /// assert!(session.data_changed());
+    ///
+    /// session.reset_data_changed();
+    /// assert!(!session.should_reissue());
+    /// # Ok(()) }) }
+    /// ```
+    pub fn mark_for_reissue(&self) {
+        self.reissue.store(true, Ordering::SeqCst);
+        self.changes.write().unwrap().reissued = true;
+    }
+
+    /// returns true if the session store has asked for this session
+    /// to be stored again, see [`Session::mark_for_reissue`]
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use async_session::Session;
+    /// # fn main() -> async_session::Result { async_std::task::block_on(async {
+    /// let session = Session::new();
+    /// assert!(!session.should_reissue());
+    /// session.mark_for_reissue();
+    /// assert!(session.should_reissue());
+    /// # Ok(()) }) }
+    /// ```
+    pub fn should_reissue(&self) -> bool {
+        self.reissue.load(Ordering::SeqCst)
+    }
+
+    /// increments the version, which session stores do before every
+    /// write
+    pub(crate) fn increment_version(&mut self) {
+        self.version += 1;
+    }
+
+    /// sets the version to the one after both the version of this
+    /// session and that of the stored copy it is about to replace,
+    /// which is 0 if there is none, so that every write to a session
+    /// store changes the stored version even if a stale copy is stored
+    pub(crate) fn advance_version(&mut self, stored: u64) {
+        self.version = stored.max(self.version) + 1;
+    }
+
+    /// sets the version, for session stores that derive it from the
+    /// version of a stored copy
+    pub(crate) fn set_version(&mut self, version: u64) {
+        self.version = version;
+    }
+
+    /// copies this session, without sharing its data or change
+    /// tracking with the copy, as if it had been stored and loaded
+    /// again
+    pub(crate) fn snapshot(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            version: self.version,
+            created: self.created,
+            expiry: self.expiry,
+            principal: self.principal.clone(),
+            data: Arc::new(RwLock::new(self.data())),
+            cookie_value: None,
+            changes: Arc::default(),
+            destroy: Arc::new(AtomicBool::new(false)),
+            reissue: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// copies this session without sharing its data or change
+    /// tracking with the copy, but unlike [`Session::snapshot`] the
+    /// copy keeps the changes of this session. storing the copy
+    /// therefore does not reset the changes of this session, which
+    /// lets a store that writes a session to several stores hand each
+    /// of them the changed keys
+    pub(crate) fn detach(&self) -> Self {
+        Self {
+            data: Arc::new(RwLock::new(self.data())),
+            changes: Arc::new(RwLock::new(self.changes.read().unwrap().clone())),
+            destroy: Arc::new(AtomicBool::new(self.is_destroyed())),
+            reissue: Arc::new(AtomicBool::new(self.should_reissue())),
+            ..self.clone()
+        }
+    }
+
+    /// returns true if the principal was set or removed since this
+    /// session was created or loaded
+    pub(crate) fn principal_changed(&self) -> bool {
+        self.changes.read().unwrap().principal
+    }
+
+    /// adds the changes of an earlier copy of this session, which is
+    /// replaced by this session before it was stored, to the changes
+    /// of this session
+    pub(crate) fn include_changes(&self, earlier: &Session) {
+        if Arc::ptr_eq(&self.changes, &earlier.changes) {
+            return;
+        }
+
+        let earlier = earlier.changes.read().unwrap().clone();
+        let mut changes = self.changes.write().unwrap();
+        changes.keys.extend(earlier.keys);
+        changes.principal |= earlier.principal;
+        changes.reissued |= earlier.reissued;
+        changes.expiry |= earlier.expiry;
+    }
+
+    /// copies this session like [`Session::snapshot`], but with the
+    /// data of a stored copy of it, to which the keys that changed in
+    /// this session are applied. the version is that of the stored
+    /// copy, and so are the principal and expiry unless they changed
+    /// in this session.
+    pub(crate) fn merge_snapshot(&self, stored: &Session) -> Self {
+        let mut data = stored.data();
+        let changes = self.changes.read().unwrap();
+        {
+            let own = self.data.read().unwrap();
+            for key in &changes.keys {
+                match own.get(key) {
+                    Some(value) => data.insert(key.clone(), value.clone()),
+                    None => data.remove(key),
+                };
+            }
+        }
+
+        let principal = if changes.principal {
+            self.principal.clone()
+        } else {
+            stored.principal.clone()
+        };
+        let expiry = if changes.expiry {
+            self.expiry
+        } else {
+            stored.expiry
+        };
+        Self {
+            version: stored.version,
+            expiry,
+            principal,
+            data: Arc::new(RwLock::new(data)),
+            ..self.snapshot()
+        }
+    }
+
+    /// returns true if storing this session would not change anything,
+    /// because it was loaded rather than created or regenerated, and
+    /// neither its data, principal nor expiry changed since
+    pub(crate) fn is_unchanged(&self) -> bool {
+        let changes = self.changes.read().unwrap();
+        self.cookie_value.is_none()
+            && changes.keys.is_empty()
+            && !changes.principal
+            && !changes.reissued
+            && !changes.expiry
+    }
+
+    /// constructs an empty session with the provided id, to destroy
+    /// a stored session that is only known by its id
+    pub(crate) fn with_id(id: impl Into<String>) -> Self {
+        let mut session = Self::new();
+        session.id = id.into();
+        session
+    }
+
+    /// replaces the id of this session, without changing its cookie
+    /// value
+    pub(crate) fn set_id(&mut self, id: String) {
+        self.id = id;
+    }
+
+    /// replaces the creation time
+    #[cfg(test)]
+    pub(crate) fn set_created(&mut self, created: DateTime) {
+        self.created = Some(created);
+    }
+
+    /// replaces the principal without marking the data as changed
+    pub(crate) fn with_principal(self, principal: Option<String>) -> Self {
+        Self { principal, ..self }
+    }
+
+    /// replaces the expiry without marking it as changed
+    pub(crate) fn with_expiry(self, expiry: Option<DateTime>) -> Self {
+        Self { expiry, ..self }
+    }
+
+    /// returns a copy of the session hashmap
+    pub(crate) fn data(&self) -> HashMap<String, String> {
+        self.data.read().unwrap().clone()
+    }
+
+    /// replaces the session hashmap without marking the data as
+    /// changed. the returned session keeps the id, expiry and cookie
+    /// value of this session, and shares its change tracking with
+    /// every clone of it.
+    pub(crate) fn with_data(self, data: HashMap<String, String>) -> Self {
+        Self {
+            data: Arc::new(RwLock::new(data)),
+            ..self
+        }
     }
 
     /// Ensures that this session is not expired. Returns None if it is expired
@@ -0,0 +1,241 @@
+use crate::Session;
+use std::ops::Bound;
+use time::OffsetDateTime;
+
+/// # A summary of a stored session
+///
+/// Returned by [`SessionStore::list_sessions`](crate::SessionStore::list_sessions)
+/// for admin tools that need to show which sessions exist without
+/// loading them by cookie value. It contains the id, creation time,
+/// expiry and principal of a session, but none of its data.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{Session, SessionInfo};
+/// # use std::time::Duration;
+/// let mut session = Session::new();
+/// session.expire_in(Duration::from_secs(60));
+/// let info = SessionInfo::new(&session);
+/// assert_eq!(session.id(), info.id());
+/// assert_eq!(session.expiry(), info.expiry());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    id: String,
+    created: Option<OffsetDateTime>,
+    expiry: Option<OffsetDateTime>,
+    principal: Option<String>,
+}
+
+impl SessionInfo {
+    /// summarizes a session
+    pub fn new(session: &Session) -> Self {
+        Self {
+            id: session.id().to_owned(),
+            created: session.created().copied(),
+            expiry: session.expiry().copied(),
+            principal: session.principal().map(str::to_owned),
+        }
+    }
+
+    /// the id of the session
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// when the session was created, if it is known
+    pub fn created(&self) -> Option<&OffsetDateTime> {
+        self.created.as_ref()
+    }
+
+    /// the expiry of the session, if it has one
+    pub fn expiry(&self) -> Option<&OffsetDateTime> {
+        self.expiry.as_ref()
+    }
+
+    /// the principal that the session belongs to, if it has one
+    pub fn principal(&self) -> Option<&str> {
+        self.principal.as_deref()
+    }
+
+    /// removes a prefix from the id and another one from the
+    /// principal, returning `None` if either does not start with its
+    /// prefix
+    pub(crate) fn strip_prefix(mut self, prefix: &str, principal_prefix: &str) -> Option<Self> {
+        self.id = self.id.strip_prefix(prefix)?.to_owned();
+        self.principal = match self.principal {
+            Some(principal) => Some(principal.strip_prefix(principal_prefix)?.to_owned()),
+            None => None,
+        };
+        Some(self)
+    }
+}
+
+impl From<&Session> for SessionInfo {
+    fn from(session: &Session) -> Self {
+        Self::new(session)
+    }
+}
+
+/// # One page of a session listing
+///
+/// Returned by [`SessionStore::list_sessions`](crate::SessionStore::list_sessions).
+/// [`SessionPage::next`] is a cursor that is passed to the next call
+/// to continue the listing where this page ended, and the listing is
+/// complete once it is `None`. Cursors are opaque and specific to the
+/// store that returned them.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{MemoryStore, Session, SessionStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// let store = MemoryStore::new();
+/// for _ in 0..5 {
+///     store.store_session(Session::new()).await?;
+/// }
+///
+/// let mut ids = vec![];
+/// let mut cursor = None;
+/// loop {
+///     let page = store.list_sessions(cursor.as_deref(), 2).await?;
+///     ids.extend(page.sessions().iter().map(|info| info.id().to_owned()));
+///     match page.next() {
+///         Some(next) => cursor = Some(next.to_owned()),
+///         None => break,
+///     }
+/// }
+/// assert_eq!(5, ids.len());
+/// # Ok(()) }) }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionPage {
+    sessions: Vec<SessionInfo>,
+    next: Option<String>,
+}
+
+impl SessionPage {
+    /// constructs a page from the sessions it contains and the cursor
+    /// that the next page starts after, if there may be one
+    pub fn new(sessions: Vec<SessionInfo>, next: Option<String>) -> Self {
+        Self { sessions, next }
+    }
+
+    /// collects a page from the first `limit` entries of an iterator,
+    /// which must be ordered by cursor. entries without a session,
+    /// such as expired sessions, count towards the limit but are left
+    /// out of the page. the iterator is advanced by at most one more
+    /// entry, to find out whether there is a next page.
+    pub(crate) fn collect(
+        entries: impl IntoIterator<Item = (String, Option<SessionInfo>)>,
+        limit: usize,
+    ) -> Self {
+        assert!(limit > 0, "session pages must have a limit of at least one");
+        let mut entries = entries.into_iter();
+        let mut page = Self::default();
+        let mut last = None;
+        for (cursor, info) in entries.by_ref().take(limit) {
+            page.sessions.extend(info);
+            last = Some(cursor);
+        }
+
+        if entries.next().is_some() {
+            page.next = last;
+        }
+        page
+    }
+
+    /// the sessions on this page
+    pub fn sessions(&self) -> &[SessionInfo] {
+        &self.sessions
+    }
+
+    /// takes the sessions on this page
+    pub fn into_sessions(self) -> Vec<SessionInfo> {
+        self.sessions
+    }
+
+    /// the cursor to pass to
+    /// [`SessionStore::list_sessions`](crate::SessionStore::list_sessions)
+    /// for the next page, or `None` if this is the last page
+    pub fn next(&self) -> Option<&str> {
+        self.next.as_deref()
+    }
+}
+
+/// The range of ids that start with a prefix and come after a cursor,
+/// for stores that list sessions in the order of their ids.
+#[derive(Debug)]
+pub(crate) struct IdRange {
+    start: Bound<String>,
+    end: Bound<String>,
+}
+
+impl IdRange {
+    /// the range of a listing of the ids with the provided prefix,
+    /// continuing after the cursor, or `None` if it is empty
+    pub(crate) fn new(prefix: &str, cursor: Option<&str>) -> Option<Self> {
+        let start = match cursor {
+            Some(cursor) if cursor >= prefix => Bound::Excluded(cursor.to_owned()),
+            _ => Bound::Included(prefix.to_owned()),
+        };
+        let end = match prefix_end(prefix) {
+            Some(end) => end,
+            None => {
+                return Some(Self {
+                    start,
+                    end: Bound::Unbounded,
+                })
+            }
+        };
+
+        let empty = match &start {
+            Bound::Included(start) | Bound::Excluded(start) => *start >= end,
+            Bound::Unbounded => false,
+        };
+        (!empty).then_some(Self {
+            start,
+            end: Bound::Excluded(end),
+        })
+    }
+
+    pub(crate) fn bounds(&self) -> (Bound<&str>, Bound<&str>) {
+        (
+            self.start.as_ref().map(String::as_str),
+            self.end.as_ref().map(String::as_str),
+        )
+    }
+}
+
+/// the smallest string that is greater than every string that starts
+/// with the prefix, or `None` if every string does
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// lists the ids of every session in a store, one page at a time
+#[cfg(test)]
+pub(crate) async fn list_ids(
+    store: &(impl crate::SessionStore + Sync),
+    limit: usize,
+) -> crate::Result<Vec<String>> {
+    let mut ids = vec![];
+    let mut cursor = None;
+    loop {
+        let page = store.list_sessions(cursor.as_deref(), limit).await?;
+        assert!(page.sessions().len() <= limit);
+        ids.extend(page.sessions().iter().map(|info| info.id().to_owned()));
+        match page.next() {
+            Some(next) => cursor = Some(next.to_owned()),
+            None => return Ok(ids),
+        }
+    }
+}
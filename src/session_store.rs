@@ -1,6 +1,14 @@
-use crate::{async_trait, Result, Session};
+use crate::{async_trait, Result, Session, SessionError, SessionInfo, SessionPage};
 
 /// An async session backend.
+///
+/// Implementations report failures with a
+/// [`SessionError`](crate::SessionError): cookie values that can not
+/// be parsed are a
+/// [`SessionError::InvalidCookie`](crate::SessionError::InvalidCookie),
+/// and failures of the underlying storage are a
+/// [`SessionError::Backend`](crate::SessionError::Backend), so that
+/// callers can tell a bad request from an outage.
 #[async_trait]
 pub trait SessionStore {
     /// Get a session from the storage backend.
@@ -10,15 +18,169 @@ pub trait SessionStore {
     /// into a session if possible
     async fn load_session(&self, cookie_value: String) -> Result<Option<Session>>;
 
+    /// Gets a session from the storage backend by its id, rather than
+    /// by the cookie value that the id is derived from with
+    /// [`Session::id_from_cookie_value`]. This is used by
+    /// [`NamespacedStore`](crate::NamespacedStore) to load sessions
+    /// under an id that it derives for its namespace.
+    ///
+    /// The default implementation returns
+    /// [`SessionError::Unsupported`](crate::SessionError::Unsupported).
+    async fn load_session_by_id(&self, id: &str) -> Result<Option<Session>> {
+        let _ = id;
+        Err(SessionError::Unsupported("loading sessions by id"))
+    }
+
     /// Store a session on the storage backend.
     ///
     /// The return value is the value of the cookie to store for the
     /// user that represents this session
     async fn store_session(&self, session: Session) -> Result<Option<String>>;
 
+    /// Stores a session like [`SessionStore::store_session`], but only
+    /// if the stored session still has the same
+    /// [version](Session::version) as the provided one, which is the
+    /// case if it has not been stored since it was loaded. A new
+    /// session is only stored if no session with its id exists. The
+    /// comparison and the write happen atomically, so that of two
+    /// requests that loaded the same version of a session only the
+    /// first one to store it succeeds, and the other one fails with
+    /// [`SessionError::Conflict`](crate::SessionError::Conflict) and
+    /// can load the session again and retry.
+    ///
+    /// The default implementation returns
+    /// [`SessionError::Unsupported`](crate::SessionError::Unsupported).
+    async fn compare_and_store(&self, session: Session) -> Result<Option<String>> {
+        let _ = session;
+        Err(SessionError::Unsupported("compare and store"))
+    }
+
     /// Remove a session from the session store
     async fn destroy_session(&self, session: Session) -> Result;
 
     /// Empties the entire store, destroying all sessions
     async fn clear_store(&self) -> Result;
+
+    /// Destroys every session whose id starts with the provided
+    /// prefix, leaving all other sessions in place. This is used by
+    /// [`NamespacedStore`](crate::NamespacedStore) to clear a single
+    /// namespace of a shared store.
+    ///
+    /// The default implementation returns
+    /// [`SessionError::Unsupported`](crate::SessionError::Unsupported).
+    async fn clear_prefix(&self, prefix: &str) -> Result {
+        let _ = prefix;
+        Err(SessionError::Unsupported("clearing sessions by prefix"))
+    }
+
+    /// Lists the sessions in the store that have not expired, ordered
+    /// in a way that is specific to the store, for admin tools. At
+    /// most `limit` sessions are returned at once: the listing starts
+    /// with `cursor` set to `None`, and continues with the cursor
+    /// returned as [`SessionPage::next`] until that is `None`.
+    /// Sessions that are stored or destroyed while the listing is in
+    /// progress may or may not be listed.
+    ///
+    /// A page may contain fewer than `limit` sessions even if it is
+    /// not the last page.
+    ///
+    /// The default implementation returns
+    /// [`SessionError::Unsupported`](crate::SessionError::Unsupported).
+    ///
+    /// # Panics
+    ///
+    /// Implementations may panic if `limit` is zero.
+    async fn list_sessions(&self, cursor: Option<&str>, limit: usize) -> Result<SessionPage> {
+        let _ = (cursor, limit);
+        Err(SessionError::Unsupported("listing sessions"))
+    }
+
+    /// Lists the sessions in the store whose ids start with `prefix`,
+    /// like [`SessionStore::list_sessions`] and with the same cursors.
+    ///
+    /// The default implementation lists a page of all sessions with
+    /// [`SessionStore::list_sessions`] and leaves out the sessions
+    /// without the prefix, so that a complete listing visits every
+    /// session in the store, and pages that are not the last page may
+    /// be empty. Stores that list sessions in the order of their ids
+    /// only visit the sessions with the prefix.
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<SessionPage> {
+        let page = self.list_sessions(cursor, limit).await?;
+        let next = page.next().map(str::to_owned);
+        let sessions = page
+            .into_sessions()
+            .into_iter()
+            .filter(|info| info.id().starts_with(prefix))
+            .collect();
+        Ok(SessionPage::new(sessions, next))
+    }
+
+    /// Lists the sessions of a principal that have not expired, see
+    /// [`Session::set_principal`], ordered by id.
+    ///
+    /// The default implementation returns
+    /// [`SessionError::Unsupported`](crate::SessionError::Unsupported).
+    async fn list_principal_sessions(&self, principal: &str) -> Result<Vec<SessionInfo>> {
+        let _ = principal;
+        Err(SessionError::Unsupported("listing sessions by principal"))
+    }
+
+    /// Destroys every session of a principal, see
+    /// [`Session::set_principal`], for example to log a user out
+    /// everywhere after they changed their password.
+    ///
+    /// The default implementation returns
+    /// [`SessionError::Unsupported`](crate::SessionError::Unsupported).
+    async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+        let _ = principal;
+        Err(SessionError::Unsupported(
+            "destroying sessions by principal",
+        ))
+    }
+}
+
+/// How a store that wraps other stores looks a session up in them,
+/// depending on how the session was requested from it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Lookup<'a> {
+    CookieValue(&'a str),
+    Id(&'a str),
+}
+
+impl Lookup<'_> {
+    pub(crate) async fn load<S>(self, store: &S) -> Result<Option<Session>>
+    where
+        S: SessionStore + Sync + ?Sized,
+    {
+        match self {
+            Lookup::CookieValue(cookie_value) => store.load_session(cookie_value.to_owned()).await,
+            Lookup::Id(id) => store.load_session_by_id(id).await,
+        }
+    }
+}
+
+/// # How a session store writes a session that is already stored
+///
+/// Chosen with
+/// [`MemoryStore::with_update_mode`](crate::MemoryStore::with_update_mode)
+/// and [`CookieStore::with_update_mode`](crate::CookieStore::with_update_mode).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum UpdateMode {
+    /// the stored data is replaced with the data of the session, so
+    /// that of two requests that change the same session concurrently
+    /// the last one to store it wins. this is the default.
+    #[default]
+    Replace,
+
+    /// only the [keys that changed](Session::changed_keys) in the
+    /// session are written to the stored data, so that concurrent
+    /// requests that change different keys of the same session do not
+    /// overwrite each other's changes
+    Merge,
 }
@@ -0,0 +1,590 @@
+use crate::{
+    async_trait, envelope::Format, log, session_info::IdRange, Bincode, Result, Session,
+    SessionCodec, SessionError, SessionInfo, SessionPage, SessionStore,
+};
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use std::{
+    ops::Bound,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use time::OffsetDateTime;
+
+/// The schema migrations of the sessions table, in order.
+///
+/// The number of migrations that have been applied to a database is
+/// recorded in its `user_version`, and the remaining ones are applied
+/// when a [`SqliteStore`] is opened. Released migrations must never be
+/// changed or removed, only appended to.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE async_sessions (
+        id TEXT PRIMARY KEY NOT NULL,
+        expiry INTEGER,
+        data BLOB NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE INDEX async_sessions_expiry ON async_sessions (expiry);
+",
+    "
+    ALTER TABLE async_sessions ADD COLUMN principal TEXT;
+    CREATE INDEX async_sessions_principal ON async_sessions (principal);
+",
+];
+
+/// how long to wait for a lock held by another connection to the same
+/// database before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// # embedded sqlite session store
+///
+/// Stores sessions in a table named `async_sessions` of a
+/// [SQLite](https://sqlite.org) database, which is compiled into this
+/// crate, so that sessions survive restarts without running a
+/// database server. This store is only available with the `sqlite`
+/// cargo feature.
+///
+/// The table has the columns `id`, `expiry`, `principal`, `data` and
+/// `updated_at`, with an index on `expiry` that
+/// [`SqliteStore::cleanup`] uses to delete expired sessions without
+/// scanning the whole table, and an index on `principal` for the
+/// sessions of a principal. Any
+/// migrations of the table are run when the store is opened. `data`
+/// holds the serialized session in the same versioned format as the
+/// [`CookieStore`](crate::CookieStore), and sessions are serialized
+/// with [`Bincode`] unless another codec is chosen with
+/// [`SqliteStore::with_codec`]. `expiry` and `updated_at` are unix
+/// timestamps in seconds.
+///
+/// SQLite calls are blocking, so they are run on a thread pool
+/// instead of the async executor. A store uses a single connection,
+/// and clones of it share that connection.
+///
+/// # Example
+///
+/// ```rust
+/// # use async_session::{Session, SessionStore, SqliteStore};
+/// # fn main() -> async_session::Result { async_std::task::block_on(async {
+/// # let dir = tempfile::tempdir().unwrap();
+/// let store = SqliteStore::open(dir.path().join("sessions.db")).await?;
+/// let mut session = Session::new();
+/// session.insert("user_id", 1)?;
+/// let cookie_value = store.store_session(session).await?.unwrap();
+///
+/// let session = store.load_session(cookie_value).await?.unwrap();
+/// assert_eq!(session.get::<usize>("user_id"), Some(1));
+/// # Ok(()) }) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SqliteStore<C = Bincode> {
+    connection: Arc<Mutex<Connection>>,
+    format: Format<C>,
+}
+
+impl SqliteStore<Bincode> {
+    /// opens the database file at `path`, creating it if it does not
+    /// exist yet, and runs any pending migrations
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        log::trace!("opening sqlite store `{}`", path.display());
+        let connection = blocking::unblock(move || Connection::open(path)).await?;
+        Self::from_connection(connection).await
+    }
+
+    /// opens a database that is only kept in memory, and is therefore
+    /// lost when the store is dropped. this is mostly useful in tests.
+    pub async fn open_in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?).await
+    }
+
+    /// uses an already opened connection, for example one that was
+    /// opened with custom flags, and runs any pending migrations
+    pub async fn from_connection(connection: Connection) -> Result<Self> {
+        let connection = blocking::unblock(move || {
+            let mut connection = connection;
+            connection.busy_timeout(BUSY_TIMEOUT)?;
+            migrate(&mut connection)?;
+            Ok::<_, SessionError>(connection)
+        })
+        .await?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            format: Format::new(Bincode),
+        })
+    }
+}
+
+impl<C: SessionCodec> SqliteStore<C> {
+    /// serializes sessions with the provided codec instead of
+    /// [`Bincode`]. sessions written with any of the built-in codecs
+    /// are recognized on load regardless of this setting.
+    pub fn with_codec<D: SessionCodec>(self, codec: D) -> SqliteStore<D> {
+        SqliteStore {
+            connection: self.connection,
+            format: self.format.with_codec(codec),
+        }
+    }
+
+    /// Deletes all expired sessions. This should be run on an
+    /// intermittent basis if this store is run for long enough that
+    /// disk usage is a concern.
+    pub async fn cleanup(&self) -> Result {
+        log::trace!("cleaning up sqlite store");
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let removed = self
+            .with_connection(move |connection| {
+                connection.execute("DELETE FROM async_sessions WHERE expiry < ?1", [now])
+            })
+            .await?;
+        log::trace!("removed {} expired sessions", removed);
+        Ok(())
+    }
+
+    /// returns the number of sessions in the store, including expired
+    /// sessions that have not been cleaned up yet
+    pub async fn count(&self) -> Result<usize> {
+        self.with_connection(|connection| {
+            connection.query_row("SELECT COUNT(*) FROM async_sessions", [], |row| row.get(0))
+        })
+        .await
+    }
+
+    /// runs a blocking function with the connection on a thread pool
+    async fn with_connection<T, E, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        E: Into<SessionError>,
+        F: FnOnce(&Connection) -> std::result::Result<T, E> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        blocking::unblock(move || {
+            let connection = connection
+                .lock()
+                .map_err(|_| SessionError::backend("sqlite connection lock was poisoned"))?;
+            f(&connection).map_err(Into::into)
+        })
+        .await
+    }
+}
+
+/// applies the migrations that have not been applied to the database
+/// yet, in a single transaction
+fn migrate(connection: &mut Connection) -> Result {
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: usize = transaction.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(SessionError::backend(format!(
+            "sqlite schema version {} is newer than the latest known version {}",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::trace!("running sqlite store migration {}", index + 1);
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    transaction.commit()?;
+    Ok(())
+}
+
+#[async_trait]
+impl<C: SessionCodec> SessionStore for SqliteStore<C> {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        self.load_session_by_id(&id).await
+    }
+
+    async fn load_session_by_id(&self, id: &str) -> Result<Option<Session>> {
+        log::trace!("loading session by id `{}`", id);
+        let id = id.to_owned();
+        let record: Option<Vec<u8>> = self
+            .with_connection(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT data FROM async_sessions WHERE id = ?1",
+                        [id],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await?;
+
+        match record {
+            Some(record) => Ok(self
+                .format
+                .decode_record(&record)?
+                .and_then(Session::validate)),
+            None => Ok(None),
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        log::trace!("storing session by id `{}`", session.id());
+        let format = self.format.clone();
+        let id = session.id().to_owned();
+        let expiry = session.expiry().map(|expiry| expiry.unix_timestamp());
+        let principal = session.principal().map(str::to_owned);
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let session = self
+            .with_connection(move |connection| {
+                let transaction =
+                    Transaction::new_unchecked(connection, TransactionBehavior::Immediate)?;
+                let stored: Option<Vec<u8>> = transaction
+                    .query_row(
+                        "SELECT data FROM async_sessions WHERE id = ?1",
+                        [&id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let mut session = session;
+                session.advance_version(format.record_version(stored.as_deref()));
+                let record = format.encode_record(&session)?;
+                transaction.execute(
+                    "INSERT INTO async_sessions (id, expiry, principal, data, updated_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5)
+                    ON CONFLICT (id) DO UPDATE SET
+                        expiry = excluded.expiry,
+                        principal = excluded.principal,
+                        data = excluded.data,
+                        updated_at = excluded.updated_at",
+                    params![id, expiry, principal, record, now],
+                )?;
+                transaction.commit()?;
+                Ok::<_, SessionError>(session)
+            })
+            .await?;
+
+        session.reset_data_changed();
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        log::trace!("destroying session by id `{}`", session.id());
+        let id = session.id().to_owned();
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM async_sessions WHERE id = ?1", [id])
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> Result {
+        log::trace!("clearing sqlite store");
+        self.with_connection(|connection| connection.execute("DELETE FROM async_sessions", []))
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_prefix(&self, prefix: &str) -> Result {
+        log::trace!(
+            "clearing sessions with prefix `{}` from sqlite store",
+            prefix
+        );
+        let prefix = prefix.to_owned();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "DELETE FROM async_sessions WHERE substr(id, 1, length(?1)) = ?1",
+                [prefix],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Sessions are listed in the order of their ids, which are also
+    /// the cursors.
+    async fn list_sessions(&self, cursor: Option<&str>, limit: usize) -> Result<SessionPage> {
+        self.list_prefix("", cursor, limit).await
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<SessionPage> {
+        let range = match IdRange::new(prefix, cursor) {
+            Some(range) => range,
+            None => return Ok(SessionPage::collect(vec![], limit)),
+        };
+        let bound = |bound: Bound<&str>| match bound {
+            Bound::Included(id) | Bound::Excluded(id) => Some(id.to_owned()),
+            Bound::Unbounded => None,
+        };
+        let (start, end) = range.bounds();
+        let (from, after) = match start {
+            Bound::Excluded(_) => (None, bound(start)),
+            _ => (bound(start), None),
+        };
+        let before = bound(end);
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let records = self
+            .with_connection(move |connection| {
+                connection
+                    .prepare(
+                        "SELECT id, data FROM async_sessions
+                        WHERE (?1 IS NULL OR id >= ?1) AND (?2 IS NULL OR id > ?2)
+                            AND (?3 IS NULL OR id < ?3) AND (expiry IS NULL OR expiry >= ?4)
+                        ORDER BY id LIMIT ?5",
+                    )?
+                    .query_map(
+                        params![from, after, before, now, limit.saturating_add(1) as i64],
+                        |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)),
+                    )?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+
+        let mut entries = vec![];
+        for (id, record) in records {
+            let session = self
+                .format
+                .decode_record(&record)?
+                .and_then(Session::validate);
+            entries.push((id, session.as_ref().map(SessionInfo::new)));
+        }
+        Ok(SessionPage::collect(entries, limit))
+    }
+
+    async fn list_principal_sessions(&self, principal: &str) -> Result<Vec<SessionInfo>> {
+        let principal = principal.to_owned();
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let records = self
+            .with_connection(move |connection| {
+                connection
+                    .prepare(
+                        "SELECT data FROM async_sessions
+                        WHERE principal = ?1 AND (expiry IS NULL OR expiry >= ?2)
+                        ORDER BY id",
+                    )?
+                    .query_map(params![principal, now], |row| row.get::<_, Vec<u8>>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+
+        let mut sessions = vec![];
+        for record in records {
+            if let Some(session) = self
+                .format
+                .decode_record(&record)?
+                .and_then(Session::validate)
+            {
+                sessions.push(SessionInfo::new(&session));
+            }
+        }
+        Ok(sessions)
+    }
+
+    async fn destroy_principal_sessions(&self, principal: &str) -> Result {
+        log::trace!("destroying sessions of principal `{}`", principal);
+        let principal = principal.to_owned();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "DELETE FROM async_sessions WHERE principal = ?1",
+                [principal],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn storing_and_loading_a_session() -> Result {
+        let store = SqliteStore::open_in_memory().await?;
+        let mut session = Session::new();
+        session.insert("key", "value")?;
+        session.expire_in(Duration::from_secs(60));
+        let (id, expiry) = (session.id().to_owned(), *session.expiry().unwrap());
+        let cookie_value = store.store_session(session).await?.unwrap();
+
+        let mut loaded = store.load_session(cookie_value.clone()).await?.unwrap();
+        assert_eq!(id, loaded.id());
+        assert_eq!(Some(&expiry), loaded.expiry());
+        assert_eq!("value", loaded.get::<String>("key").unwrap());
+
+        loaded.insert("key", "other value")?;
+        assert_eq!(None, store.store_session(loaded).await?);
+        let loaded = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!("other value", loaded.get::<String>("key").unwrap());
+        assert_eq!(1, store.count().await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn loading_missing_and_expired_sessions() -> Result {
+        let store = SqliteStore::open_in_memory().await?;
+        let cookie_value = Session::new().into_cookie_value().unwrap();
+        assert!(store.load_session(cookie_value).await?.is_none());
+
+        let mut session = Session::new();
+        session.expire_in(Duration::from_secs(60));
+        let cookie_value = store.store_session(session).await?.unwrap();
+        let mut session = store.load_session(cookie_value.clone()).await?.unwrap();
+        session.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(1));
+        store.store_session(session).await?;
+        assert!(store.load_session(cookie_value).await?.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn versioning_stale_writes() -> Result {
+        let store = SqliteStore::open_in_memory().await?;
+        let session = Session::new();
+        let stale = session.clone();
+        let cookie_value = store.store_session(session).await?.unwrap();
+        store.store_session(stale.clone()).await?;
+        store.store_session(stale).await?;
+        let session = store.load_session(cookie_value).await?.unwrap();
+        assert_eq!(3, session.version());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn destroying_and_clearing_sessions() -> Result {
+        let store = SqliteStore::open_in_memory().await?;
+        let cookie_value = store.store_session(Session::new()).await?.unwrap();
+        for _ in 0..3i8 {
+            store.store_session(Session::new()).await?;
+        }
+
+        let session = store.load_session(cookie_value.clone()).await?.unwrap();
+        store.destroy_session(session).await?;
+        assert!(store.load_session(cookie_value).await?.is_none());
+        assert_eq!(3, store.count().await?);
+
+        let mut session = Session::new();
+        session.set_id(format!("app.{}", session.id()));
+        session.expire_in(Duration::from_secs(60));
+        store.store_session(session).await?;
+        store.clear_prefix("app.").await?;
+        assert_eq!(3, store.count().await?);
+
+        store.clear_store().await?;
+        assert_eq!(0, store.count().await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn listing_sessions_in_pages() -> Result {
+        let store = SqliteStore::open_in_memory().await?;
+        let mut ids = vec![];
+        for _ in 0..5i8 {
+            let session = Session::new();
+            ids.push(session.id().to_owned());
+            store.store_session(session).await?;
+        }
+        let mut session = Session::new();
+        session.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(5));
+        store.store_session(session).await?;
+
+        ids.sort();
+        assert_eq!(ids, crate::session_info::list_ids(&store, 2).await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn listing_sessions_by_prefix() -> Result {
+        let store = SqliteStore::open_in_memory().await?;
+        let mut ids = vec![];
+        for prefix in ["a.", "b.", "b.", "c."] {
+            let mut session = Session::new();
+            session.set_id(format!("{}{}", prefix, session.id()));
+            if prefix == "b." {
+                ids.push(session.id().to_owned());
+            }
+            store.store_session(session).await?;
+        }
+        ids.sort();
+
+        let page = store.list_prefix("b.", None, 1).await?;
+        assert_eq!(ids[0], page.sessions()[0].id());
+        let page = store.list_prefix("b.", page.next(), 1).await?;
+        assert_eq!(ids[1], page.sessions()[0].id());
+        assert_eq!(None, page.next());
+        assert!(store
+            .list_prefix("d.", None, 5)
+            .await?
+            .sessions()
+            .is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn cleaning_up_expired_sessions() -> Result {
+        let store = SqliteStore::open_in_memory().await?;
+        store.store_session(Session::new()).await?;
+        let mut session = Session::new();
+        session.expire_in(Duration::from_secs(60));
+        store.store_session(session).await?;
+        let mut session = Session::new();
+        session.set_expiry(OffsetDateTime::now_utc() - Duration::from_secs(5));
+        store.store_session(session).await?;
+
+        store.cleanup().await?;
+        assert_eq!(2, store.count().await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn migrating_on_open() -> Result {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions.db");
+        let cookie_value = SqliteStore::open(&path)
+            .await?
+            .store_session(Session::new())
+            .await?
+            .unwrap();
+
+        let store = SqliteStore::open(&path).await?;
+        assert!(store.load_session(cookie_value).await?.is_some());
+        let version: usize = store
+            .with_connection(|connection| {
+                connection.query_row("PRAGMA user_version", [], |row| row.get(0))
+            })
+            .await?;
+        assert_eq!(MIGRATIONS.len(), version);
+
+        let connection = Connection::open(&path)?;
+        connection.pragma_update(None, "user_version", MIGRATIONS.len() + 1)?;
+        assert!(SqliteStore::from_connection(connection).await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn indexing_sessions_by_principal() -> Result {
+        let connection = Connection::open_in_memory()?;
+        connection.execute_batch(MIGRATIONS[0])?;
+        connection.pragma_update(None, "user_version", 1)?;
+        let store = SqliteStore::from_connection(connection).await?;
+
+        let mut ids = vec![];
+        for _ in 0..3i8 {
+            let mut session = Session::new();
+            session.set_principal("user");
+            ids.push(session.id().to_owned());
+            store.store_session(session).await?;
+        }
+        let mut session = Session::new();
+        session.set_principal("other");
+        store.store_session(session).await?;
+        ids.sort();
+
+        let listed = store.list_principal_sessions("user").await?;
+        let listed: Vec<_> = listed.iter().map(|info| info.id().to_owned()).collect();
+        assert_eq!(ids, listed);
+
+        store.destroy_principal_sessions("user").await?;
+        assert!(store.list_principal_sessions("user").await?.is_empty());
+        assert_eq!(1, store.count().await?);
+        Ok(())
+    }
+}